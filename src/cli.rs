@@ -1,6 +1,10 @@
-use clap::{command, Parser};
+use std::{path::PathBuf, time::Duration};
+
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
 use regex::Regex;
-use colored::Colorize;
+use colored::{Color, Colorize};
+
+use crate::{printer::Paint, spacing::Spacing};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -20,7 +24,7 @@ pub struct Args {
     #[arg(
         short = 'r', long, num_args = 2, 
         value_names = ["opening_pattern", "closing_pattern"],
-        help = "List of pairs of regex patterns to format against"
+        help = "List of pairs of regex patterns to format against. Closing pattern may reference groups of the opening one with `\\1` or `${name}`"
     )]
     patterns_regex: Vec<String>,
 
@@ -35,13 +39,70 @@ pub struct Args {
     #[arg(
         visible_alias = "lr", long, num_args = 2, 
         value_names = ["opening_pattern", "closing_pattern"],
-        help = "List of pairs of regex patterns of string literals, formatting in which will be ignored",
+        help = "List of pairs of regex patterns of string literals, formatting in which will be ignored. Closing pattern may reference groups of the opening one with `\\1` or `${name}`",
     )]
     literals_regex: Vec<String>,
 
+    #[arg(
+        short = 'k', long, num_args = 2,
+        value_names = ["opening_keywords", "closing_keyword"],
+        help = "List of pairs of keywords to format against, matched only on identifier boundaries. Several comma-separated opening keywords may share one closing keyword (`-k def,class,do end`)",
+    )]
+    keywords: Vec<String>,
+
+    #[arg(
+        long, num_args = 4,
+        value_names = ["name", "role", "opening_pattern", "closing_pattern"],
+        help = "List of named pairs of plain-text patterns. Role is one of: bracket, string, comment, interpolation (bracket inside of a string, like `${` and `}`)",
+    )]
+    pair: Vec<String>,
+
+    #[arg(
+        long, num_args = 4,
+        value_names = ["name", "role", "opening_pattern", "closing_pattern"],
+        help = "List of named pairs of regex patterns, same as `--pair`",
+    )]
+    pair_regex: Vec<String>,
+
     #[arg(short, long, help = "Size of the minimum block to be formatted", default_value = "20")]
     min_block_size: usize,
 
+    #[arg(long, value_name = "cols", help = "Wrap lines longer than this at whitespace, continuing them with an extra indent. Literals are never broken")]
+    max_width: Option<usize>,
+
+    #[arg(long, value_name = "bytes", help = "Most input held back while deciding whether a block fits on a single line. Larger blocks are always broken, so memory stays bounded for any input", default_value = "65536")]
+    max_lookahead: usize,
+
+    #[arg(long, visible_alias = "preserve-newlines", help = "Never remove line breaks of the input, like the ones inside of S-expressions that fit on a single line. Blocks still add line breaks of their own, to only fix indentation use `--reindent`")]
+    keep_line_breaks: bool,
+
+    #[arg(long, value_name = "N", help = "Collapse longer runs of blank lines outside of literals into N blank lines")]
+    max_blank_lines: Option<usize>,
+
+    #[arg(long, help = "Only fix the indentation of every line by the blocks open at its start, like `indent-region` of editors. Line breaks are never added or removed")]
+    reindent: bool,
+
+    #[arg(long, help = "Remove whitespace at the end of lines, outside of literals")]
+    trim_trailing_whitespace: bool,
+
+    #[arg(long, help = "End the output with exactly one line break")]
+    final_newline: bool,
+
+    #[arg(long, value_enum, help = "Write every line break as this. `auto` uses the style of the first line break of the input", default_value = "auto")]
+    line_ending: LineEnding,
+
+    #[arg(long, value_name = "operator", help = "Put exactly one space on both sides of this operator, outside of literals. May be given several times (`--space-around = --space-around ->`)")]
+    space_around: Vec<String>,
+
+    #[arg(long, value_name = "token", help = "Remove spaces before this token and put exactly one space after it, outside of literals. May be given several times")]
+    space_after: Vec<String>,
+
+    #[arg(long, help = "Collapse runs of whitespace inside of lines into a single space, outside of literals")]
+    collapse_spaces: bool,
+
+    #[arg(long, help = "Shorthand for spaces around `=`, `+` and `->`, after `,` and `:` and `--collapse-spaces`")]
+    normalize_spacing: bool,
+
     #[arg(long, help = "Size of the window for regexes to be applied to", default_value = "100")]
     regex_window_size: usize,
 
@@ -50,28 +111,332 @@ pub struct Args {
 
     #[arg(short = 'e', long, help = "Patterns (brackets) prepended with this will be ignored (escape sequences will be left unchanged)", default_value = "\\")]
     escape_sequence: String,
+
+    #[arg(
+        short = 'd', long, num_args = 2,
+        value_names = ["pattern", "style"],
+        help = "List of plain-text delimiters inside of blocks and how they are followed: `break` (line break, like list items) or `space` (single space)",
+    )]
+    delimiter: Vec<String>,
+
+    #[arg(long, help = "Closing of an outer pair also closes the pairs inside of it that were left open (like `<p>` in HTML)")]
+    close_unclosed: bool,
+
+    #[arg(long, value_enum, help = "How blocks between opening and closing patterns are laid out", default_value = "break-both")]
+    block_style: BlockStyle,
+
+    #[arg(
+        long, num_args = 2,
+        value_names = ["name", "style"],
+        help = "Overrides `--block-style` for pairs with the given name. Pairs that were not named with `--pair` are named by their opening pattern",
+    )]
+    pair_style: Vec<String>,
+
+    #[arg(
+        long, num_args = 2,
+        value_names = ["name", "color"],
+        help = "Colour of pairs with the given name (`red`, `bright blue`, ... or `dimmed`). By default brackets are coloured by depth, strings by pair and comments are dimmed",
+    )]
+    pair_color: Vec<String>,
+
+    #[arg(long, value_enum, help = "Colour brackets by depth and highlight literals. `auto` colours only terminals and respects `NO_COLOR`", default_value = "auto")]
+    color: ColorChoice,
+
+    #[arg(long, help = "Regex that separates independent records (like lines of a log). All pairs that are still open are dropped at every separator, so one unbalanced record does not affect the next ones")]
+    record_separator: Option<String>,
+
+    #[arg(long, conflicts_with = "record_separator", help = "Format every line as an independent record, same as `--record-separator '\n'`")]
+    per_line: bool,
+
+    #[arg(long, help = "Regex of the spans to format, everything else is printed as is. If it has a group named `select`, only that group is formatted (`--select 'payload=(?<select>.*)'`). The regex only sees the scanning window (about 3 KB by default, grows with `--regex-window-size`): longer spans never match and are printed as is")]
+    select: Option<String>,
+
+    #[arg(long, help = "Regex of a prefix at the start of every input line (like a timestamp and a level of a log line), that is repeated on every line the input line is broken into. Patterns are not matched across line breaks with it")]
+    line_prefix: Option<String>,
+
+    #[arg(long, value_enum, help = "How the `--line-prefix` is repeated on the continuation lines", default_value = "repeat")]
+    prefix_style: PrefixStyle,
+
+    #[arg(long, help = "Low-latency mode for live streams (`tail -f app.log | format-brackets --stream`): every complete line is formatted and printed as soon as it is read, instead of waiting for the whole window to fill")]
+    stream: bool,
+
+    #[arg(long, value_name = "file", help = "Read the file instead of `stdin` and keep waiting for new lines, like `tail -f`. Follows the file through rotation and truncation. Implies `--stream`")]
+    follow: Option<PathBuf>,
+
+    #[arg(long, value_name = "ms", help = "Print the last incomplete line of a stream if no new input arrives for this many milliseconds, even if a pattern could still continue in it. Implies `--stream`")]
+    flush_timeout: Option<u64>,
+
+    #[arg(long, help = "Instead of formatting, print every segment the input was split into on its own line, along with its kind and the stack of open pairs. Whitespace is made visible")]
+    debug_view: bool,
+
+    #[arg(long, conflicts_with = "debug_view", help = "Instead of formatting, describe every decision of the scanner: which patterns were found where, which one matched and the stack before and after. Lists patterns that never matched at the end")]
+    explain: bool,
+
+    #[arg(short = 'M', long, value_enum, help = "Preset of patterns and literals. Replaces the default `-p`/`-l` lists unless they are given explicitly", default_value = "default")]
+    mode: Mode,
     
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Brackets, strings and comments of C-like languages
+    Default,
+    /// XML/HTML tags: `<name ...>` is closed by the matching `</name>` (in any case), `<x/>` and HTML void elements like `<br>` are left inline.
+    /// Closing of an outer tag also closes the unclosed ones inside of it
+    Tags,
+    /// Lisp, Clojure and EDN S-expressions
+    Lisp,
+    /// JSON, including truncated, concatenated or otherwise invalid one
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStyle {
+    /// Line breaks after the opening and before the closing pattern
+    BreakBoth,
+    /// Line break after the opening pattern, closing one stays on the last line
+    BreakAfterOpen,
+    /// Opening pattern on its own line, then line breaks like `break-both`
+    Allman,
+    /// No line breaks, lines of the block are aligned to the column after the opening pattern
+    Hang,
+    /// No line breaks, lines of the block are only indented
+    Inline,
+    /// S-expression: kept on one line if it fits in `--min-block-size`, otherwise arguments are aligned under the first one
+    Lisp,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Block that is formatted
+    Bracket,
+    /// Literal that is printed as is
+    String,
+    /// Literal that is printed as is and dimmed
+    Comment,
+    /// Bracket inside of a string, contents of which are scanned for brackets and literals again
+    Interpolation,
+}
+
+impl Role {
+    /// Role of a pair given with `-l` or `--lr`.
+    fn of_literal(opening: &Pattern, closing: &Pattern) -> Role {
+        let is_comment = closing.source() == "\n" || ["/*", "<!--", "#|", "#="].contains(&opening.source());
+
+        if is_comment {
+            Role::Comment
+        } else {
+            Role::String
+        }
+    }
+
+    /// Whether contents of the pair are printed as is.
+    pub fn is_literal(self) -> bool {
+        matches!(self, Role::String | Role::Comment)
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DelimiterStyle {
+    /// Line break after the delimiter
+    Break,
+    /// Exactly one space after the delimiter
+    Space,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixStyle {
+    /// Prefix itself
+    Repeat,
+    /// Blank space of the same width
+    Align,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    /// Same as the input
+    Auto,
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    Crlf,
+    /// `\r`
+    Cr,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Auto | LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+}
+
+/// Patterns and literals a `Mode` brings in addition to (or instead of) the command line ones.
+struct Preset {
+    patterns: &'static [&'static str],
+    patterns_regex: &'static [&'static str],
+    literals: &'static [&'static str],
+    literals_regex: &'static [&'static str],
+    delimiters: &'static [&'static str],
+    block_style: Option<BlockStyle>,
+    close_unclosed: bool,
+}
+
+impl Mode {
+    fn preset(self) -> Option<Preset> {
+        match self {
+            Mode::Default => None,
+            Mode::Tags => Some(Preset {
+                patterns: &[],
+                patterns_regex: &[
+                    concat!(
+                        r"<(?:(?<void>(?i:area|base|br|col|embed|hr|img|input|link|meta|param|source|track|wbr))\b[^<>]*",
+                        r"|(?<name>[A-Za-z_][\w:.-]*)(?:\s+[^<>]*[^/<>])?\s*)>",
+                    ),
+                    r"(?i)</${name}\s*>",
+                ],
+                literals: &["<!--", "-->",   "<![CDATA[", "]]>",   "<?", "?>",   "<!", ">"],
+                literals_regex: &[],
+                delimiters: &[],
+                block_style: None,
+                close_unclosed: true,
+            }),
+            Mode::Lisp => Some(Preset {
+                patterns: &["(", ")",   "[", "]",   "{", "}"],
+                patterns_regex: &[],
+                literals: &["\"", "\"",   ";", "\n",   "#|", "|#"],
+                literals_regex: &[],
+                delimiters: &[],
+                block_style: Some(BlockStyle::Lisp),
+                close_unclosed: false,
+            }),
+            Mode::Json => Some(Preset {
+                patterns: &["{", "}",   "[", "]"],
+                patterns_regex: &[],
+                literals: &["\"", "\""],
+                literals_regex: &[],
+                delimiters: &[",", "break",   ":", "space"],
+                block_style: None,
+                close_unclosed: false,
+            }),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Pattern {
     PlainText(String),
     Regex(Regex),
+    /// Closing regex that references capture groups of the opening regex (`\1`, `${name}`).
+    /// Never matches by itself - it is resolved into a `Pattern::Regex` once the opening pattern is found.
+    Template(String),
+    /// Plain-text word that only matches on identifier boundaries (`end` does not match inside `endpoint`)
+    Keyword(String),
 }
 
 impl Pattern {
-    pub fn find_in(&self, text: &str) -> Option<(usize, usize)> {
+    /// `preceding` is the character right before `text`, if any - it decides identifier boundaries at its start.
+    pub fn find_in(&self, text: &str, preceding: Option<char>) -> Option<(usize, usize)> {
         match self {
             Pattern::PlainText(pat) =>
                 text.find(pat).map(|start| (start, start + pat.len())),
 
             Pattern::Regex(regex) =>
                 regex.find(text).map(|mat| (mat.start(), mat.end())),
+
+            Pattern::Template(_) => None,
+
+            Pattern::Keyword(word) => text.match_indices(word.as_str())
+                .map(|(start, _)| (start, start + word.len()))
+                .find(|&(start, end)| {
+                    let before = text[..start].chars().next_back().or(preceding);
+                    let after = text[end..].chars().next();
+
+                    !before.is_some_and(is_identifier_char) && !after.is_some_and(is_identifier_char)
+                }),
         }
     } 
+
+    /// Builds the closing pattern for an `opening` pattern that was found at the start of `text`.
+    pub fn resolve_closing(&self, opening: &Pattern, text: &str) -> Pattern {
+        let Pattern::Template(template) = self else {
+            return self.clone();
+        };
+
+        let captures = opening.regex().and_then(|regex| regex.captures(text));
+        let expanded = expand_references(template, |name| {
+            let group = captures.as_ref().and_then(|captures| match name.parse::<usize>() {
+                Ok(index) => captures.get(index),
+                Err(_) => captures.name(name),
+            });
+
+            regex::escape(group.map_or("", |mat| mat.as_str()))
+        });
+
+        match Regex::new(&expanded) {
+            Ok(regex) => Pattern::Regex(regex),
+            Err(_) => Pattern::PlainText(expanded),
+        }
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Replaces `\N` and `${name}` references in `template` with the text returned by `group`.
+fn expand_references(template: &str, mut group: impl FnMut(&str) -> String) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(pos) = rest.find(['\\', '$']) {
+        result.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+
+        let (reference, skip) = if let Some(after) = tail.strip_prefix('\\') {
+            let digits = after.find(|c: char| !c.is_ascii_digit()).unwrap_or(after.len());
+            if digits > 0 {
+                (Some(&after[..digits]), 1 + digits)
+            } else {
+                // Keep other escapes (`\d`, `\\`, ...) intact
+                (None, 1 + after.chars().next().map_or(0, char::len_utf8))
+            }
+        } else {
+            match tail.strip_prefix("${").and_then(|after| after.find('}').map(|len| &after[..len])) {
+                Some(name) => (Some(name), 3 + name.len()),
+                None => (None, 1),
+            }
+        };
+
+        match reference {
+            Some(name) => result.push_str(&group(name)),
+            None => result.push_str(&tail[..skip]),
+        }
+        rest = &tail[skip..];
+    }
+
+    result.push_str(rest);
+    result
 }
 
 impl Pattern {
+    /// Text the pattern was defined with.
+    pub fn source(&self) -> &str {
+        match self {
+            Pattern::PlainText(text) | Pattern::Template(text) | Pattern::Keyword(text) => text,
+            Pattern::Regex(regex) => regex.as_str(),
+        }
+    }
     pub fn is_plaintext(&self) -> bool {
         self.plaintext().is_some()
     }
@@ -85,6 +450,14 @@ impl Pattern {
     pub fn is_regex(&self) -> bool {
         self.regex().is_some()
     }
+    /// Whether this opening regex matched at the start of `text` with its `void` group, so that nothing is opened (HTML `<br>`).
+    pub fn is_void(&self, text: &str) -> bool {
+        self.regex()
+            .filter(|regex| regex.capture_names().any(|name| name == Some("void")))
+            .and_then(|regex| regex.captures(text))
+            .is_some_and(|captures| captures.name("void").is_some())
+    }
+
     pub fn regex(&self) -> Option<&Regex> {
         if let Pattern::Regex(regex) = self {
             Some(regex)
@@ -94,24 +467,62 @@ impl Pattern {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct PatternPair {
+    pub name: String,
+    pub role: Role,
+    pub opening: Pattern,
+    pub closing: Pattern,
+    /// Layout of the block, for brackets
+    pub style: BlockStyle,
+    /// Colour that overrides the default one of the role
+    pub paint: Option<Paint>,
+}
+
 #[derive(Debug, Clone)]
 pub struct ArgsStructured {
-    pub patterns: Vec<(Pattern, Pattern)>,
-    pub literals: Vec<(Pattern, Pattern)>,
+    pub pairs: Vec<PatternPair>,
+    pub delimiters: Vec<(Pattern, DelimiterStyle)>,
+    /// Resets all open pairs
+    pub record_separator: Option<Pattern>,
+    /// Only spans matching this are formatted
+    pub select: Option<Regex>,
+    /// Prefix of every record, repeated on its continuation lines
+    pub line_prefix: Option<Regex>,
+    pub prefix_style: PrefixStyle,
+    /// Read and print as soon as something is available
+    pub stream: bool,
+    pub follow: Option<PathBuf>,
+    /// How long to wait for the rest of an incomplete line
+    pub flush_timeout: Option<Duration>,
     pub min_block_size: usize,
+    pub max_width: Option<usize>,
+    pub max_lookahead: usize,
+    pub keep_line_breaks: bool,
+    pub max_blank_lines: Option<usize>,
+    pub reindent: bool,
+    pub trim_trailing_whitespace: bool,
+    pub final_newline: bool,
+    pub line_ending: LineEnding,
+    pub spacing: Spacing,
+    /// Closing of an outer pair closes the inner ones too
+    pub close_unclosed: bool,
     pub regex_window_size: usize,
     pub disallow_escaping: bool,
     pub escape_sequence: String,
+    pub color: ColorChoice,
+    pub debug_view: bool,
+    pub explain: bool,
 }
 
 impl ArgsStructured {
     pub fn all_patterns_iterator<'a>(&'a self) -> Box<dyn 'a + Iterator<Item = &'a Pattern>> {
-        let iter = self.patterns.iter()
-            .chain(self.literals.iter())
-            .map(
-                |(a, b)| [a, b].into_iter()
+        let iter = self.pairs.iter()
+            .flat_map(
+                |pair| [&pair.opening, &pair.closing].into_iter()
             )
-            .flatten();
+            .chain(self.delimiters.iter().map(|(delimiter, _)| delimiter))
+            .chain(self.record_separator.iter());
     
         Box::new(iter)
     }
@@ -120,10 +531,15 @@ impl ArgsStructured {
 #[derive(Debug, Clone)]
 pub enum Error {
     RegexesNotCompiled(Vec<(String, regex::Error)>),
+    UnknownValue {
+        kind: &'static str,
+        value: String,
+        possible: String,
+    },
 }
 
-impl ToString for Error {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::RegexesNotCompiled(items) => {
                 let mut string = "".to_owned(); 
@@ -144,91 +560,383 @@ impl ToString for Error {
                     }
                 }
 
-                string
+                f.write_str(&string)
+            },
+            Error::UnknownValue { kind, value, possible } => {
+                write!(f, "{} '{}'. Possible values: {}", format!("Unknown {}", kind).red(), value.blue(), possible)
             },
         }
     }
 }
 
+fn possible_values<T: ValueEnum>() -> String {
+    T::value_variants().iter()
+        .filter_map(|variant| variant.to_possible_value())
+        .map(|value| value.get_name().to_owned())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn parse_value<T: ValueEnum>(kind: &'static str, value: &str) -> Result<T, Error> {
+    T::from_str(value, true).map_err(|_| Error::UnknownValue {
+        kind,
+        value: value.to_owned(),
+        possible: possible_values::<T>(),
+    })
+}
+
+fn parse_paint(value: &str) -> Result<Paint, Error> {
+    if value.eq_ignore_ascii_case("dimmed") {
+        return Ok(Paint::Dimmed);
+    }
+
+    value.parse::<Color>()
+        .map(Paint::Color)
+        .map_err(|_| Error::UnknownValue {
+            kind: "colour",
+            value: value.to_owned(),
+            possible: "dimmed, black, red, green, yellow, blue, magenta, cyan, white and their `bright` variants".to_owned(),
+        })
+}
+
 pub fn parse() -> Result<ArgsStructured, Error> {
-    structure(Args::parse())
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    apply_preset(&mut args, &matches);
+
+    structure(args)
+}
+
+fn apply_preset(args: &mut Args, matches: &ArgMatches) {
+    let Some(preset) = args.mode.preset() else {
+        return;
+    };
+    let is_default = |id: &str| matches.value_source(id) == Some(ValueSource::DefaultValue);
+    let to_strings = |texts: &[&str]| texts.iter().map(|text| text.to_string()).collect::<Vec<_>>();
+
+    if is_default("patterns") {
+        args.patterns = to_strings(preset.patterns);
+    }
+    if is_default("literals") {
+        args.literals = to_strings(preset.literals);
+    }
+    if let Some(block_style) = preset.block_style.filter(|_| is_default("block_style")) {
+        args.block_style = block_style;
+    }
+    args.patterns_regex.extend(to_strings(preset.patterns_regex));
+    args.literals_regex.extend(to_strings(preset.literals_regex));
+    args.delimiter.extend(to_strings(preset.delimiters));
+    args.close_unclosed |= preset.close_unclosed;
 }
 
 pub fn structure(args: Args) -> Result<ArgsStructured, Error> {
     let mut regex_errors= vec![]; 
 
-    // Patterns
-    let mut patterns: Vec<(Pattern, Pattern)> = Vec::with_capacity(args.patterns.len() / 2 + args.patterns_regex.len() / 2);
+    // Brackets
+    let mut brackets: Vec<(Pattern, Pattern)> = Vec::with_capacity(args.patterns.len() / 2 + args.patterns_regex.len() / 2);
     {
         // Plaintext
         let plaintext = args.patterns.into_iter()
-            .map(|x| Pattern::PlainText(x))
+            .map(Pattern::PlainText)
             .array_chunks::<2>()
             .map(|[a, b]| (a, b));
-        patterns.extend(plaintext);
+        brackets.extend(plaintext);
 
 
-        let regexes =  args.patterns_regex.into_iter()
-            .filter_map(
-                |text| {
-                    match Regex::new(&text) {
-                        Ok(r) => Some(r),
-                        Err(err) => {
-                            regex_errors.push((text, err));
-                            None
-                        },
-                    }
-                }
-            );
-
         // Regexes
-        for [open, close] in regexes.array_chunks::<2>() {
-            let pair = (Pattern::Regex(open), Pattern::Regex(close));
-            patterns.push(pair);
+        brackets.extend(compile_regex_pairs(args.patterns_regex, &mut regex_errors));
+
+        // Keywords
+        for [openings, closing] in args.keywords.into_iter().array_chunks::<2>() {
+            let keywords = openings.split(',')
+                .map(str::trim)
+                .filter(|opening| !opening.is_empty())
+                .map(|opening| (Pattern::Keyword(opening.to_owned()), Pattern::Keyword(closing.clone())));
+            brackets.extend(keywords);
         }
     }
-    let patterns = patterns;
 
     // Literals
     let mut literals: Vec<(Pattern, Pattern)> = Vec::with_capacity(args.literals.len() / 2 + args.literals_regex.len() / 2);
     {
         let plaintext = args.literals.into_iter()
-            .map(|x| Pattern::PlainText(x))
+            .map(Pattern::PlainText)
             .array_chunks::<2>()
             .map(|[a, b]| (a, b));
         literals.extend(plaintext);
 
-        let regexes =  args.literals_regex.into_iter()
-            .filter_map(
-                |text| {
-                    match Regex::new(&text) {
-                        Ok(r) => Some(r),
-                        Err(err) => {
-                            regex_errors.push((text, err));
-                            None
-                        },
-                    }
-                }
-            )
-            .map(|x| Pattern::Regex(x))
-            .array_chunks::<2>()
-            .map(|[a, b]| (a, b));
-        literals.extend(regexes);
+        literals.extend(compile_regex_pairs(args.literals_regex, &mut regex_errors));
+    }
+
+    // Named pairs
+    let mut named = Vec::with_capacity(args.pair.len() / 4 + args.pair_regex.len() / 4);
+    for [name, role, opening, closing] in args.pair.into_iter().array_chunks::<4>() {
+        let role = parse_value::<Role>("role", &role)?;
+        named.push((name, role, Pattern::PlainText(opening), Pattern::PlainText(closing)));
+    }
+    for [name, role, opening, closing] in args.pair_regex.into_iter().array_chunks::<4>() {
+        let role = parse_value::<Role>("role", &role)?;
+        if let Some((opening, closing)) = compile_regex_pair(opening, closing, &mut regex_errors) {
+            named.push((name, role, opening, closing));
+        }
     }
-    let literals = literals;
+
+    // Records
+    let record_separator = match (args.record_separator, args.per_line) {
+        (Some(separator), _) => match Regex::new(&separator) {
+            Ok(regex) => Some(Pattern::Regex(regex)),
+            Err(err) => {
+                regex_errors.push((separator, err));
+                None
+            },
+        },
+        (None, true) => Some(Pattern::PlainText("\n".to_owned())),
+        (None, false) => None,
+    };
+
+    let select = args.select.and_then(|select| match Regex::new(&select) {
+        Ok(regex) => Some(regex),
+        Err(err) => {
+            regex_errors.push((select, err));
+            None
+        },
+    });
+
+    let line_prefix = args.line_prefix.and_then(|prefix| match Regex::new(&prefix) {
+        Ok(regex) => Some(regex),
+        Err(err) => {
+            regex_errors.push((prefix, err));
+            None
+        },
+    });
 
     if !regex_errors.is_empty() {
         return Err(Error::RegexesNotCompiled(regex_errors));
     }
 
+    // Options of pairs, by name
+    let mut pair_styles = Vec::with_capacity(args.pair_style.len() / 2);
+    for [name, style] in args.pair_style.into_iter().array_chunks::<2>() {
+        pair_styles.push((name, parse_value::<BlockStyle>("block style", &style)?));
+    }
+
+    let mut pair_colors = Vec::with_capacity(args.pair_color.len() / 2);
+    for [name, color] in args.pair_color.into_iter().array_chunks::<2>() {
+        pair_colors.push((name, parse_paint(&color)?));
+    }
+
+    let unnamed = brackets.into_iter()
+        .map(|(opening, closing)| (Role::Bracket, opening, closing))
+        .chain(literals.into_iter().map(|(opening, closing)| (Role::of_literal(&opening, &closing), opening, closing)))
+        .map(|(role, opening, closing)| (opening.source().to_owned(), role, opening, closing));
+
+    let pairs = unnamed.chain(named)
+        .map(|(name, role, opening, closing)| {
+            PatternPair {
+                style: pair_option(&pair_styles, &name).unwrap_or(args.block_style),
+                paint: pair_option(&pair_colors, &name),
+                name,
+                role,
+                opening,
+                closing,
+            }
+        })
+        .collect();
+
+    // Spacing
+    let mut spacing = Spacing {
+        around: args.space_around,
+        after: args.space_after,
+        collapse: args.collapse_spaces,
+    };
+    if args.normalize_spacing {
+        spacing.around.extend(["=", "+", "->"].map(str::to_owned));
+        spacing.after.extend([",", ":"].map(str::to_owned));
+        spacing.collapse = true;
+    }
+
+    // Delimiters
+    let mut delimiters = Vec::with_capacity(args.delimiter.len() / 2);
+    for [delimiter, style] in args.delimiter.into_iter().array_chunks::<2>() {
+        delimiters.push((Pattern::PlainText(delimiter), parse_value::<DelimiterStyle>("delimiter style", &style)?));
+    }
+
     Ok(
         ArgsStructured {
-            patterns,
-            literals,
+            pairs,
+            delimiters,
+            record_separator,
+            select,
+            line_prefix,
+            prefix_style: args.prefix_style,
+            stream: args.stream || args.follow.is_some() || args.flush_timeout.is_some(),
+            follow: args.follow,
+            flush_timeout: args.flush_timeout.map(Duration::from_millis),
             min_block_size: args.min_block_size,
+            max_width: args.max_width,
+            max_lookahead: args.max_lookahead,
+            keep_line_breaks: args.keep_line_breaks,
+            max_blank_lines: args.max_blank_lines,
+            reindent: args.reindent,
+            trim_trailing_whitespace: args.trim_trailing_whitespace,
+            final_newline: args.final_newline,
+            line_ending: args.line_ending,
+            spacing,
+            close_unclosed: args.close_unclosed,
             regex_window_size: args.regex_window_size,
             disallow_escaping: args.disallow_escaping,
             escape_sequence: args.escape_sequence,
+            color: args.color,
+            debug_view: args.debug_view,
+            explain: args.explain,
         }
     )
-} 
\ No newline at end of file
+}
+
+/// Value of the option that was given for the pair last.
+fn pair_option<T: Copy>(options: &[(String, T)], name: &str) -> Option<T> {
+    options.iter()
+        .rev()
+        .find(|(option_name, _)| option_name == name)
+        .map(|(_, value)| *value)
+}
+
+fn compile_regex_pairs(texts: Vec<String>, regex_errors: &mut Vec<(String, regex::Error)>) -> Vec<(Pattern, Pattern)> {
+    texts.into_iter()
+        .array_chunks::<2>()
+        .filter_map(|[open, close]| compile_regex_pair(open, close, regex_errors))
+        .collect()
+}
+
+fn compile_regex_pair(open: String, close: String, regex_errors: &mut Vec<(String, regex::Error)>) -> Option<(Pattern, Pattern)> {
+    let open = match Regex::new(&open) {
+        Ok(regex) => regex,
+        Err(err) => {
+            regex_errors.push((open, err));
+            return None;
+        },
+    };
+
+    let mut has_references = false;
+    let validation_text = expand_references(&close, |_| {
+        has_references = true;
+        String::new()
+    });
+
+    // Templates can only be compiled once the opening pattern matches, so check them with empty groups
+    let close = match (Regex::new(&validation_text), has_references) {
+        (Ok(_), true) => Pattern::Template(close),
+        (Ok(regex), false) => Pattern::Regex(regex),
+        (Err(err), _) => {
+            regex_errors.push((close, err));
+            return None;
+        },
+    };
+
+    Some((Pattern::Regex(open), close))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(template: &str) -> String {
+        expand_references(template, |name| format!("<{name}>"))
+    }
+
+    #[test]
+    fn expands_numbered_references() {
+        assert_eq!(expand(r"\]\1\]"), r"\]<1>\]");
+        assert_eq!(expand(r"\12x"), "<12>x");
+    }
+
+    #[test]
+    fn keeps_other_escapes() {
+        assert_eq!(expand(r"\\1"), r"\\1");
+        assert_eq!(expand(r"\d+\."), r"\d+\.");
+    }
+
+    #[test]
+    fn expands_named_references() {
+        assert_eq!(expand("(?m)^${tag}$"), "(?m)^<tag>$");
+    }
+
+    #[test]
+    fn keeps_bare_dollars() {
+        assert_eq!(expand("a$"), "a$");
+        assert_eq!(expand("$b ${c"), "$b ${c");
+    }
+
+    fn tag_pair() -> (Pattern, Pattern) {
+        let preset = Mode::Tags.preset().unwrap();
+        let opening = Pattern::Regex(Regex::new(preset.patterns_regex[0]).unwrap());
+        (opening, Pattern::Template(preset.patterns_regex[1].to_owned()))
+    }
+
+    #[test]
+    fn void_tags_open_nothing() {
+        let (opening, _) = tag_pair();
+        assert_eq!(opening.find_in("<br>", None), Some((0, 4)));
+        assert!(opening.is_void("<br>"));
+        assert!(opening.is_void("<IMG src=\"a.png\">"));
+        assert!(!opening.is_void("<brand>"));
+    }
+
+    #[test]
+    fn self_closing_tags_are_not_opened() {
+        let (opening, _) = tag_pair();
+        assert_eq!(opening.find_in("<x/>", None), None);
+        assert_eq!(opening.find_in("<x a=\"b\" />", None), None);
+    }
+
+    #[test]
+    fn tags_are_closed_by_name_in_any_case() {
+        let (opening, closing) = tag_pair();
+        let text = "<a b=\"c\">x</A>";
+        assert_eq!(opening.find_in(text, None), Some((0, 9)));
+        assert!(!opening.is_void(text));
+
+        let closing = closing.resolve_closing(&opening, text);
+        assert_eq!(closing.find_in(&text[9..], None), Some((1, 5)));
+        assert_eq!(closing.find_in("</ab>", None), None);
+    }
+
+    fn keyword(word: &str) -> Pattern {
+        Pattern::Keyword(word.to_owned())
+    }
+
+    #[test]
+    fn keywords_match_on_identifier_boundaries() {
+        assert_eq!(keyword("end").find_in("endpoint end", None), Some((9, 12)));
+        assert_eq!(keyword("end").find_in("_end end_ end", None), Some((10, 13)));
+        assert_eq!(keyword("end").find_in("x.end", None), Some((2, 5)));
+        assert_eq!(keyword("end").find_in("ending", None), None);
+    }
+
+    #[test]
+    fn keywords_use_the_preceding_character() {
+        assert_eq!(keyword("end").find_in("end", Some('b')), None);
+        assert_eq!(keyword("end").find_in("end", Some(' ')), Some((0, 3)));
+        assert_eq!(keyword("end").find_in("end end", Some('_')), Some((4, 7)));
+    }
+
+    #[test]
+    fn keep_line_breaks_has_the_old_name() {
+        let args = Args::try_parse_from(["format-brackets", "--preserve-newlines"]).unwrap();
+        assert!(args.keep_line_breaks);
+    }
+
+    #[test]
+    fn keywords_may_share_a_closing() {
+        let args = Args::try_parse_from(["format-brackets", "-k", "def,class,do", "end"]).unwrap();
+        let args = structure(args).unwrap();
+
+        let keywords = args.pairs.iter()
+            .filter_map(|pair| match (&pair.opening, &pair.closing) {
+                (Pattern::Keyword(opening), Pattern::Keyword(closing)) => Some((opening.as_str(), closing.as_str())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(keywords, [("def", "end"), ("class", "end"), ("do", "end")]);
+    }
+}
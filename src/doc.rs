@@ -0,0 +1,248 @@
+//! Document algebra of the printer, after Wadler's "A prettier printer".
+//!
+//! A group is built from text, lines and nested groups while it is held back by the printer. Its lines are
+//! broken all or none: flat, a `line` is a space and a `softline` is nothing. A `hardline` is a line break of
+//! the input, so the group can never be flat. The width of the flat group is known at every point of the
+//! building, so the printer can give up on a group as soon as it gets too wide (like Oppen's printer does).
+
+/// Part of a document, with texts of type `T`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Doc<T> {
+    /// Never broken
+    Text(T),
+    /// Space, or a line break if the enclosing group is broken
+    Line,
+    /// Nothing, or a line break if the enclosing group is broken
+    SoftLine,
+    /// Line break in any case
+    HardLine,
+    /// Lines of a group are broken all or none
+    Group(Vec<Doc<T>>),
+    /// Lines inside are indented one level deeper when broken
+    Nest(Vec<Doc<T>>),
+}
+
+/// What a document is printed as on a single line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flat<'a, T> {
+    Text(&'a T),
+    Space,
+}
+
+impl<T> Doc<T> {
+    /// Whether it is a kind of a line.
+    fn is_line(&self) -> bool {
+        matches!(self, Doc::Line | Doc::SoftLine | Doc::HardLine)
+    }
+
+    /// Which of the adjacent lines is kept.
+    fn strength(&self) -> usize {
+        match self {
+            Doc::Line => 1,
+            Doc::SoftLine => 2,
+            Doc::HardLine => 3,
+            _ => 0,
+        }
+    }
+
+    /// Calls `f` with every piece of the documents printed on a single line, in order.
+    pub fn flatten<E>(docs: &[Doc<T>], f: &mut impl FnMut(Flat<'_, T>) -> Result<(), E>) -> Result<(), E> {
+        for doc in docs {
+            match doc {
+                Doc::Text(text) => f(Flat::Text(text))?,
+                Doc::Line | Doc::HardLine => f(Flat::Space)?,
+                Doc::SoftLine => {},
+                Doc::Group(docs) | Doc::Nest(docs) => Self::flatten(docs, f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds a group of the form `group(open, nest(softline, ...), softline, close)` piece by piece.
+///
+/// Adjacent lines are a single one: a `softline` or a `hardline` wins over a `line`, so whitespace next
+/// to the brackets of a group is dropped when it is flat.
+#[derive(Debug)]
+pub struct Builder<T> {
+    /// Lists of the groups and of their nests that are not closed yet, the outermost first
+    open: Vec<Vec<Doc<T>>>,
+    /// Documents that are complete
+    done: Vec<Doc<T>>,
+    /// Width of everything built so far, printed on a single line
+    width: usize,
+    /// Whether a `hardline` was added, so the outermost group is never flat
+    is_broken: bool,
+}
+
+impl<T> Default for Builder<T> {
+    fn default() -> Self {
+        Self { open: Vec::new(), done: Vec::new(), width: 0, is_broken: false }
+    }
+}
+
+impl<T> Builder<T> {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.is_broken
+    }
+
+    /// Count of groups that are open.
+    pub fn depth(&self) -> usize {
+        self.open.len() / 2
+    }
+
+    fn current(&mut self) -> &mut Vec<Doc<T>> {
+        self.open.last_mut().unwrap_or(&mut self.done)
+    }
+
+    pub fn text(&mut self, text: T, width: usize) {
+        self.width += width;
+        self.current().push(Doc::Text(text));
+    }
+
+    pub fn line(&mut self) {
+        self.push_line(Doc::Line);
+    }
+
+    pub fn hard_line(&mut self) {
+        self.is_broken = true;
+        self.push_line(Doc::HardLine);
+    }
+
+    /// Drops a `line` right before the cursor, for text that is attached to the preceding one.
+    pub fn drop_line(&mut self) {
+        if matches!(self.current().last(), Some(Doc::Line)) {
+            self.current().pop();
+            self.width -= 1;
+        }
+    }
+
+    fn push_line(&mut self, line: Doc<T>) {
+        let docs = self.open.last_mut().unwrap_or(&mut self.done);
+        match docs.last() {
+            Some(last) if last.is_line() => {
+                // Stronger one of the two is kept
+                if line.strength() > last.strength() {
+                    if matches!(last, Doc::Line) {
+                        self.width -= 1;
+                    }
+                    *docs.last_mut().unwrap() = line;
+                }
+            },
+            _ => {
+                if matches!(line, Doc::Line) {
+                    self.width += 1;
+                }
+                docs.push(line);
+            },
+        }
+    }
+
+    /// Opens a group with the given text, the rest of the group is nested.
+    pub fn open(&mut self, text: T, width: usize) {
+        self.open.push(Vec::new());
+        self.text(text, width);
+        self.open.push(Vec::new());
+        self.push_line(Doc::SoftLine);
+    }
+
+    /// Closes the innermost group with the given text.
+    pub fn close(&mut self, text: T, width: usize) {
+        if self.open.is_empty() {
+            return self.text(text, width);
+        }
+
+        self.drop_line();
+        let nest = self.open.pop().unwrap_or_default();
+        let group = self.open.last_mut().unwrap_or(&mut self.done);
+        group.push(Doc::Nest(nest));
+        self.push_line(Doc::SoftLine);
+        self.text(text, width);
+
+        let group = self.open.pop().unwrap_or_default();
+        self.current().push(Doc::Group(group));
+    }
+
+    /// Documents built so far, with the groups that are still open closed as they are.
+    pub fn finish(mut self) -> Vec<Doc<T>> {
+        while let Some(docs) = self.open.pop() {
+            let doc = match self.open.len() % 2 {
+                0 => Doc::Group(docs),
+                _ => Doc::Nest(docs),
+            };
+            self.current().push(doc);
+        }
+        self.done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flat(docs: &[Doc<&str>]) -> String {
+        let mut out = String::new();
+        Doc::flatten(docs, &mut |piece| {
+            out.push_str(match piece {
+                Flat::Text(text) => text,
+                Flat::Space => " ",
+            });
+            Ok::<_, ()>(())
+        }).unwrap();
+        out
+    }
+
+    #[test]
+    fn lines_next_to_brackets_are_soft() {
+        let mut builder = Builder::default();
+        builder.open("(", 1);
+        builder.line();
+        builder.text("a", 1);
+        builder.line();
+        builder.line();
+        builder.text("b", 1);
+        builder.line();
+        builder.close(")", 1);
+
+        assert_eq!(builder.width(), 5);
+        let docs = builder.finish();
+        assert_eq!(flat(&docs), "(a b)");
+        assert_eq!(docs, [Doc::Group(vec![
+            Doc::Text("("),
+            Doc::Nest(vec![Doc::SoftLine, Doc::Text("a"), Doc::Line, Doc::Text("b")]),
+            Doc::SoftLine,
+            Doc::Text(")"),
+        ])]);
+    }
+
+    #[test]
+    fn attached_text_drops_the_line() {
+        let mut builder = Builder::default();
+        builder.open("[", 1);
+        builder.text("a", 1);
+        builder.line();
+        builder.drop_line();
+        builder.text(",", 1);
+        builder.line();
+        builder.text("b", 1);
+        builder.close("]", 1);
+
+        assert_eq!(builder.width(), 6);
+        assert_eq!(flat(&builder.finish()), "[a, b]");
+    }
+
+    #[test]
+    fn hard_lines_break_the_group() {
+        let mut builder = Builder::default();
+        builder.open("{", 1);
+        builder.line();
+        builder.hard_line();
+        assert!(builder.is_broken());
+        assert_eq!(builder.depth(), 1);
+        assert_eq!(builder.width(), 1);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{collections::HashSet, io::Write};
+
+use crate::{cli::ArgsStructured, printer::SegmentKind, write_escaped_whitespace, Frame};
+
+/// Pattern that the scanner was looking for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Candidate {
+    RecordSeparator,
+    Escape,
+    /// Closing of a pair by its index in `ArgsStructured::pairs`
+    Closing(usize),
+    /// Opening of a pair by its index in `ArgsStructured::pairs`
+    Opening(usize),
+    /// Delimiter by its index in `ArgsStructured::delimiters`
+    Delimiter(usize),
+}
+
+/// Pattern that was found in the current window, at bytes `start..end`
+#[derive(Debug, Clone, Copy)]
+pub struct Found {
+    pub candidate: Candidate,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Describes every decision of the scanner, for `--explain`.
+pub struct Explainer {
+    step: usize,
+    offset: usize,
+    matched: HashSet<Candidate>,
+    /// Pair indices on the stack before the current iteration
+    stack_before: Vec<usize>,
+    /// Patterns found during the current iteration
+    found: Vec<Found>,
+}
+
+impl Explainer {
+    pub fn new(writer: &mut impl Write) -> std::io::Result<Self> {
+        writeln!(writer, "Patterns are checked in order: record separator, escape sequence, closing of the innermost pair, openings, delimiters, literal openings.")?;
+        writeln!(writer, "The first one found at offset 0 wins, except that a longer literal opening wins over an opening of a bracket.")?;
+        writeln!(writer, "Otherwise text is consumed up to the nearest found pattern.")?;
+
+        Ok(Self { step: 0, offset: 0, matched: HashSet::new(), stack_before: Vec::new(), found: Vec::new() })
+    }
+
+    /// Remembers the stack before an iteration. Scanner should record found patterns into the returned list.
+    pub(crate) fn begin(&mut self, stack: &[Frame]) -> &mut Vec<Found> {
+        self.stack_before.clear();
+        self.stack_before.extend(stack.iter().map(|frame| frame.index));
+        self.found.clear();
+        &mut self.found
+    }
+
+    /// Describes a single iteration of the scanner, after it was done.
+    pub(crate) fn step(
+        &mut self, writer: &mut impl Write, args: &ArgsStructured,
+        stack: &[Frame], kind: SegmentKind, segment: &[u8]
+    ) -> std::io::Result<()> {
+        writeln!(writer)?;
+        writeln!(writer, "#{} at byte {}, stack {}", self.step, self.offset, stack_names(args, self.stack_before.iter().copied()))?;
+
+        // Patterns are recorded in the order they were checked, and the scanner returns
+        // as soon as something is found at offset 0, so it can only be the last one.
+        // Closing of an empty block also wins after the whitespace inside of it
+        let found = &self.found;
+        let winner = found.last().filter(|found| found.start == 0 || kind == SegmentKind::Closing);
+        for found in found {
+            writeln!(writer, "  found {} at {}..{}", describe(args, found.candidate), found.start, found.end)?;
+        }
+
+        match winner {
+            Some(winner) => {
+                self.matched.insert(winner.candidate);
+                match winner.start {
+                    0 if segment.is_empty() =>
+                        writeln!(writer, "  -> {} matched at offset 0, closes the innermost pair that was left open", describe(args, winner.candidate))?,
+                    0 => writeln!(writer, "  -> {} matched at offset 0", describe(args, winner.candidate))?,
+                    _ => writeln!(writer, "  -> only whitespace up to {}, empty block", describe(args, winner.candidate))?,
+                }
+            },
+            None => match found.iter().min_by_key(|found| found.start) {
+                Some(nearest) if nearest.start == segment.len() =>
+                    writeln!(writer, "  -> nothing at offset 0, text up to {}", describe(args, nearest.candidate))?,
+                _ if matches!(kind, SegmentKind::Prefix(_)) =>
+                    writeln!(writer, "  -> prefix of the record")?,
+                _ if kind == SegmentKind::RecordEnd =>
+                    writeln!(writer, "  -> outside of the selected spans, passed through")?,
+                _ =>
+                    writeln!(writer, "  -> nothing at offset 0, text up to the end of the window")?,
+            },
+        }
+
+        write!(writer, "  {} {} bytes: ", kind_name(args, kind), segment.len())?;
+        write_escaped_whitespace(writer, segment)?;
+        writeln!(writer)?;
+        writeln!(writer, "  stack after {}", stack_names(args, stack.iter().map(|frame| frame.index)))?;
+
+        self.step += 1;
+        self.offset += segment.len();
+        Ok(())
+    }
+
+    /// Lists the patterns that never won at offset 0.
+    pub fn finish(self, writer: &mut impl Write, args: &ArgsStructured) -> std::io::Result<()> {
+        let separator = args.record_separator.as_ref().map(|_| Candidate::RecordSeparator);
+        let escape = (!args.disallow_escaping && !args.escape_sequence.is_empty())
+            .then_some(Candidate::Escape);
+        let pairs = (0..args.pairs.len())
+            .flat_map(|idx| [Candidate::Opening(idx), Candidate::Closing(idx)]);
+        let delimiters = (0..args.delimiters.len()).map(Candidate::Delimiter);
+
+        writeln!(writer)?;
+        writeln!(writer, "Never matched:")?;
+        for candidate in separator.into_iter().chain(escape).chain(pairs).chain(delimiters) {
+            if !self.matched.contains(&candidate) {
+                writeln!(writer, "  {}", describe(args, candidate))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn describe(args: &ArgsStructured, candidate: Candidate) -> String {
+    match candidate {
+        Candidate::RecordSeparator => format!("record separator {:?}", args.record_separator.as_ref().map_or("", |separator| separator.source())),
+        Candidate::Escape => format!("escape sequence {:?}", args.escape_sequence),
+        Candidate::Closing(idx) => {
+            let pair = &args.pairs[idx];
+            format!("closing {:?} of {:?}", pair.closing.source(), pair.name)
+        },
+        Candidate::Opening(idx) => {
+            let pair = &args.pairs[idx];
+            format!("opening {:?} of {:?}", pair.opening.source(), pair.name)
+        },
+        Candidate::Delimiter(idx) => format!("delimiter {:?}", args.delimiters[idx].0.source()),
+    }
+}
+
+fn kind_name(args: &ArgsStructured, kind: SegmentKind) -> String {
+    match kind {
+        SegmentKind::Text => "text".to_owned(),
+        SegmentKind::Literal(idx) => format!("literal {:?}", args.pairs[idx].name),
+        SegmentKind::Opening(idx, style) => format!("opening {:?} ({:?})", args.pairs[idx].name, style),
+        SegmentKind::Closing => "closing".to_owned(),
+        SegmentKind::Delimiter(style) => format!("delimiter ({:?})", style),
+        SegmentKind::RecordEnd => "record end".to_owned(),
+        SegmentKind::Prefix(style) => format!("prefix ({:?})", style),
+    }
+}
+
+fn stack_names(args: &ArgsStructured, stack: impl Iterator<Item = usize>) -> String {
+    let names = stack
+        .map(|idx| format!("{:?}", args.pairs[idx].name))
+        .collect::<Vec<_>>();
+    format!("[{}]", names.join(" "))
+}
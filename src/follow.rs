@@ -0,0 +1,63 @@
+use std::{fs::{File, Metadata}, io::{ErrorKind, Read}, path::PathBuf, thread, time::Duration};
+
+/// How often a file that has no new data is checked again.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reads a file and keeps waiting for it to grow, like `tail -f`. Never reaches the end.
+/// If the file is truncated or replaced by a new one (log rotation), the new contents are read from the start.
+pub struct FollowReader {
+    path: PathBuf,
+    file: File,
+    /// Count of bytes read from the current file
+    position: u64,
+}
+
+impl FollowReader {
+    pub fn open(path: PathBuf) -> std::io::Result<Self> {
+        let file = File::open(&path)?;
+        Ok(Self { path, file, position: 0 })
+    }
+
+    fn was_rotated(&self) -> std::io::Result<bool> {
+        let metadata = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // Old file was moved away, but the new one is not created yet
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        Ok(metadata.len() < self.position || !is_same_file(&metadata, &self.file.metadata()?))
+    }
+}
+
+impl Read for FollowReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let was_read = self.file.read(buf)?;
+            if was_read > 0 || buf.is_empty() {
+                self.position += was_read as u64;
+                return Ok(was_read);
+            }
+
+            if self.was_rotated()? {
+                self.file = File::open(&self.path)?;
+                self.position = 0;
+                continue;
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_: &Metadata, _: &Metadata) -> bool {
+    // Only truncation can be detected
+    true
+}
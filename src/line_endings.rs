@@ -0,0 +1,183 @@
+use std::{io::Read, sync::{Arc, OnceLock}};
+
+use crate::cli::LineEnding;
+
+/// Most converted input held back while a lone `\r` waits for the line break that tells what it is.
+const MAX_UNDECIDED: usize = 64 * 1024;
+
+/// Converts line breaks of the input to `\n`, so that patterns and the printer only ever see those.
+/// Style of the line breaks is detected from the first one of the input, to write the output in the same style.
+/// Lone `\r` is a line break only if no `\n` follows it, otherwise it is a part of the text (like in progress bars).
+/// Until that is known, the input from the first lone `\r` on is held back (up to `MAX_UNDECIDED` bytes).
+pub struct LineEndingReader<R: Read> {
+    inner: R,
+    detected: Arc<OnceLock<LineEnding>>,
+    /// `\r` at the end of the last read, that may be a part of `\r\n`
+    held_cr: bool,
+    /// Converted input that was not read yet
+    pending: Vec<u8>,
+    pending_start: usize,
+    /// Start of the input in `pending` that is held back until the style is detected
+    undecided_start: Option<usize>,
+    raw: Box<[u8]>,
+}
+
+impl<R: Read> LineEndingReader<R> {
+    pub fn new(inner: R, detected: Arc<OnceLock<LineEnding>>) -> Self {
+        Self {
+            inner,
+            detected,
+            held_cr: false,
+            pending: Vec::new(),
+            pending_start: 0,
+            undecided_start: None,
+            raw: vec![0u8; 8 * 1024].into_boxed_slice(),
+        }
+    }
+
+    /// Settles the style, unless it was already detected. Lone `\r`s held back so far become line breaks with `Cr`.
+    fn decide(&mut self, line_ending: LineEnding) {
+        let line_ending = *self.detected.get_or_init(|| line_ending);
+        let Some(start) = self.undecided_start.take() else {
+            return;
+        };
+        if line_ending == LineEnding::Cr {
+            // Every `\r\n` is already converted, so these are all lone
+            self.pending[start..].iter_mut()
+                .filter(|byte| **byte == b'\r')
+                .for_each(|byte| *byte = b'\n');
+        }
+    }
+
+    /// Lone `\r` is a line break only in files that use it as one
+    fn push_cr(&mut self) {
+        match self.detected.get() {
+            Some(LineEnding::Cr) => self.pending.push(b'\n'),
+            Some(_) => self.pending.push(b'\r'),
+            None => {
+                self.undecided_start.get_or_insert(self.pending.len());
+                self.pending.push(b'\r');
+            },
+        }
+    }
+
+    fn convert(&mut self, was_read: usize) {
+        for pos in 0..was_read {
+            let byte = self.raw[pos];
+
+            if std::mem::take(&mut self.held_cr) {
+                if byte == b'\n' {
+                    self.pending.push(b'\n');
+                    self.decide(LineEnding::Crlf);
+                    continue;
+                }
+                self.push_cr();
+            }
+
+            match byte {
+                b'\r' => self.held_cr = true,
+                b'\n' => {
+                    self.pending.push(b'\n');
+                    self.decide(LineEnding::Lf);
+                },
+                byte => self.pending.push(byte),
+            }
+        }
+
+        // Lone `\r`s without any line break for this long are the line breaks
+        if self.undecided_start.is_some_and(|start| self.pending.len() - start > MAX_UNDECIDED) {
+            self.decide(LineEnding::Cr);
+        }
+    }
+}
+
+impl<R: Read> Read for LineEndingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pending_start >= self.undecided_start.unwrap_or(self.pending.len()) && !buf.is_empty() {
+            self.pending.drain(..self.pending_start);
+            self.undecided_start = self.undecided_start.map(|start| start - self.pending_start);
+            self.pending_start = 0;
+
+            let was_read = self.inner.read(&mut self.raw)?;
+            if was_read == 0 {
+                if std::mem::take(&mut self.held_cr) {
+                    self.push_cr();
+                }
+                // Lone `\r` followed by no line break at all
+                if self.undecided_start.is_some() {
+                    self.decide(LineEnding::Cr);
+                }
+                if self.pending.is_empty() {
+                    return Ok(0);
+                }
+            }
+            self.convert(was_read);
+        }
+
+        let end = self.undecided_start.unwrap_or(self.pending.len());
+        let pending = &self.pending[self.pending_start..end];
+        let len = buf.len().min(pending.len());
+        buf[..len].copy_from_slice(&pending[..len]);
+        self.pending_start += len;
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Returns every chunk from a separate read.
+    struct Chunks(VecDeque<&'static str>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some(chunk) = self.0.pop_front() else {
+                return Ok(0);
+            };
+            buf[..chunk.len()].copy_from_slice(chunk.as_bytes());
+            Ok(chunk.len())
+        }
+    }
+
+    fn convert(chunks: &[&'static str]) -> (String, Option<LineEnding>) {
+        let detected = Arc::new(OnceLock::new());
+        let mut reader = LineEndingReader::new(Chunks(chunks.iter().copied().collect()), detected.clone());
+        let mut result = String::new();
+        reader.read_to_string(&mut result).unwrap();
+        (result, detected.get().copied())
+    }
+
+    #[test]
+    fn lone_cr_in_lf_input_is_text() {
+        assert_eq!(
+            convert(&["progress 10%\rprogress 100%\nf(x)\n"]),
+            ("progress 10%\rprogress 100%\nf(x)\n".to_owned(), Some(LineEnding::Lf)),
+        );
+    }
+
+    #[test]
+    fn lone_cr_waits_for_a_line_break_in_a_later_read() {
+        assert_eq!(convert(&["a\rb", "c\nd\n"]), ("a\rbc\nd\n".to_owned(), Some(LineEnding::Lf)));
+        assert_eq!(convert(&["a\rb", "c", "\rd"]), ("a\nbc\nd".to_owned(), Some(LineEnding::Cr)));
+    }
+
+    #[test]
+    fn crlf_split_between_reads() {
+        assert_eq!(convert(&["a\r", "\nb\r\n"]), ("a\nb\n".to_owned(), Some(LineEnding::Crlf)));
+    }
+
+    #[test]
+    fn cr_input() {
+        assert_eq!(convert(&["a\rb\r"]), ("a\nb\n".to_owned(), Some(LineEnding::Cr)));
+        assert_eq!(convert(&["a\r", "b"]), ("a\nb".to_owned(), Some(LineEnding::Cr)));
+    }
+
+    #[test]
+    fn no_line_breaks() {
+        assert_eq!(convert(&["a", "b"]), ("ab".to_owned(), None));
+    }
+}
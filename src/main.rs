@@ -1,17 +1,31 @@
 #![allow(incomplete_features)]
 #![feature(iter_array_chunks, generic_const_exprs, ascii_char)]
 
-use std::{io::{stdin, stdout, BufReader, BufWriter, Read, Write}, process::ExitCode, usize};
+use std::{io::{stdin, stdout, BufReader, BufWriter, ErrorKind, Read, Write}, process::ExitCode, sync::{Arc, OnceLock}};
 
-use cli::ArgsStructured;
-use colored::Colorize;
-use printer::Printer;
+use follow::FollowReader;
+use line_endings::LineEndingReader;
+use output::Normalizer;
+use timeout_reader::TimeoutReader;
+
+use cli::{ArgsStructured, ColorChoice, Pattern, Role};
+use explain::{Candidate, Explainer, Found};
+use colored::{Color, Colorize};
 use regex::Regex;
+use printer::{Layout, Paint, Palette, Printer, SegmentKind};
 use sliding_window::SlidingWindow;
+use spacing::Spacing;
 
 pub mod cli;
 pub mod sliding_window;
 pub mod printer;
+pub mod doc;
+pub mod explain;
+pub mod follow;
+pub mod timeout_reader;
+pub mod output;
+pub mod line_endings;
+pub mod spacing;
 
 fn main() -> ExitCode {
     match inner_main() {
@@ -25,18 +39,49 @@ fn main() -> ExitCode {
 
 fn pick_window_size(args: &ArgsStructured) -> usize {
     let min_plaintext_window_bytes = args.all_patterns_iterator()
-        .filter_map(|x| x.plaintext())
-        .map(|x| x.len())
+        .filter_map(|x| match x {
+            Pattern::PlainText(text) | Pattern::Keyword(text) => Some(text.len()),
+            _ => None,
+        })
         .max()
         .unwrap_or(1);
 
     let bytes_per_char = 4; // UTF8 allows at most 4 bytes per character.
 
-    let window_size = min_plaintext_window_bytes
+    min_plaintext_window_bytes
         .max(args.regex_window_size * bytes_per_char)
-        .max(args.min_block_size * bytes_per_char);
+        .max(args.min_block_size * bytes_per_char)
+}
 
-    window_size
+fn pick_palette(args: &ArgsStructured) -> Option<Palette> {
+    match args.color {
+        ColorChoice::Always => colored::control::set_override(true),
+        ColorChoice::Never => colored::control::set_override(false),
+        ColorChoice::Auto => {},
+    }
+    if !colored::control::SHOULD_COLORIZE.should_colorize() {
+        return None;
+    }
+
+    let brackets = [Color::Yellow, Color::Magenta, Color::Blue, Color::Cyan];
+    let strings = [Color::Green, Color::BrightYellow, Color::BrightCyan, Color::BrightMagenta];
+
+    let mut strings = strings.into_iter().cycle();
+
+    let pairs = args.pairs.iter()
+        .map(|pair| match pair.role {
+            _ if pair.paint.is_some() => pair.paint,
+            Role::Bracket => None,
+            Role::String => strings.next().map(Paint::Color),
+            Role::Comment => Some(Paint::Dimmed),
+            Role::Interpolation => Some(Paint::Color(Color::BrightBlue)),
+        })
+        .collect();
+
+    Some(Palette {
+        brackets: brackets.into_iter().map(Paint::Color).collect(),
+        pairs,
+    })
 }
 
 fn merge_min(val: &mut usize, with: Option<(usize, usize)>) {
@@ -51,20 +96,51 @@ fn inner_main() -> Result<(), String> {
     // == Setup
     let window_size = pick_window_size(&args) * 4; // 4 - arbitrary number. More = less performance overhead
 
-    let reader = stdin();
+    let reader: Box<dyn Read + Send> = match &args.follow {
+        Some(path) => Box::new(FollowReader::open(path.clone()).map_err(|x| format!("{}: {}", path.display(), x))?),
+        None => Box::new(stdin()),
+    };
+    let line_ending = Arc::new(OnceLock::new());
+    let reader: Box<dyn Read + Send> = Box::new(LineEndingReader::new(reader, line_ending.clone()));
+    let reader: Box<dyn Read> = match args.flush_timeout {
+        Some(timeout) => Box::new(TimeoutReader::new(reader, timeout)),
+        None => reader,
+    };
     let reader = BufReader::new(reader);
     let mut reader = SlidingWindow::new(window_size * 2, reader);
-    reader.fill().map_err(|x| x.to_string())?;
-
-    let mut writer = BufWriter::new(stdout());
-    let mut printer = Printer::new(args.min_block_size, writer); 
+    reader.set_partial(args.stream);
+
+    let writer = BufWriter::new(stdout());
+    let writer = Normalizer::new(writer, args.line_ending, line_ending, args.final_newline);
+    let layout = Layout {
+        min_block_size: args.min_block_size,
+        max_width: args.max_width,
+        max_lookahead: args.max_lookahead,
+        keep_line_breaks: args.keep_line_breaks,
+        max_blank_lines: args.max_blank_lines,
+        reindent_only: args.reindent,
+        trim_trailing_whitespace: args.trim_trailing_whitespace,
+        spacing: args.spacing.clone(),
+    };
+    let mut printer = Printer::new(layout, pick_palette(&args), writer); 
 
 
     // == Main loop
-    let mut literal_stack = Vec::<usize>::new();
-    let mut patterns_stack = Vec::<usize>::new();
+    let mut stack = Vec::<Frame>::new();
+    let mut preceding: Option<char> = None;
 
     // let mut buf = vec![0u8; window_size].into_boxed_slice();
+    let mut segment = Vec::<u8>::new();
+    let mut explainer = match args.explain {
+        true => Some(Explainer::new(printer.writer()).map_err(|x| x.to_string())?),
+        false => None,
+    };
+    // Next `--select`ed span, relative to the start of the window
+    let mut selection: Option<(usize, usize)> = None;
+    // Whether the window starts a line of the input, that may have a `--line-prefix`
+    let mut at_line_start = true;
+    // No new input arrived in `--flush-timeout`, so an incomplete line is scanned as well
+    let mut is_idle = false;
     let mut should_stop = false;
     while !should_stop {
         // let read = reader.read(&mut buf).map_err(|x| x.to_string())?;
@@ -75,136 +151,454 @@ fn inner_main() -> Result<(), String> {
         //     Ok(ok) => ok,
         //     Err(err) => unsafe { str::from_utf8_unchecked(&buf[..err.valid_up_to()]) },
         // };
-        reader.fill().map_err(|x| x.to_string())?;
+        if args.stream {
+            // Only complete lines are scanned, so that patterns are not split between reads.
+            // Everything printed so far is shown before waiting for more.
+            loop {
+                let window = reader.get_window();
+                let is_ready = reader.has_ended() || reader.is_full() || window.contains(&b'\n')
+                    || (is_idle && !window.is_empty());
+                if is_ready {
+                    break;
+                }
+
+                printer.flush().map_err(|x| x.to_string())?;
+                match reader.fill() {
+                    Ok(()) => is_idle = false,
+                    Err(err) if err.kind() == ErrorKind::TimedOut => is_idle = true,
+                    Err(err) => return Err(err.to_string()),
+                }
+            }
+        } else {
+            reader.fill().map_err(|x| x.to_string())?;
+        }
+
         let text = reader.get_window_utf8();
-        if text.len() == 0 {
+        let text = match text.rfind('\n') {
+            Some(pos) if args.stream && !reader.has_ended() && !reader.is_full() && !is_idle => &text[..pos + 1],
+            _ => text,
+        };
+        if text.is_empty() {
             break;
         }
 
-        let (bytes_to_consume, is_closing) = single_iteration(&args, text, window_size, &mut literal_stack, &mut patterns_stack);
-
-        // if literal_stack.len() > 0 {
-        //     writer.write(format!(" {} ", bytes_to_consume).on_blue().to_string().as_bytes()).unwrap();
-        // } else if patterns_stack.len() > 0 {
-        //     writer.write(format!(" {} ", bytes_to_consume).on_yellow().to_string().as_bytes()).unwrap();
-        // } else {
-        //     writer.write(format!(" {} ", bytes_to_consume).on_bright_white().to_string().as_bytes()).unwrap();
-        // }
-
-        let is_literal = literal_stack.len() > 0;
-        let indent_level = patterns_stack.len();
+        // Text outside of the selected spans is passed through
+        if let Some(select) = args.select.as_ref().filter(|_| selection.is_none()) {
+            selection = find_selection(select, text);
+        }
+        // Passed through line by line, so that every line may start a record
+        let passthrough = |len: usize| text[..len].find('\n').map_or(len, |pos| pos + 1);
+        let selected = match (&args.select, selection) {
+            (None, _) => Ok(text),
+            (Some(_), Some((0, end))) => Ok(&text[..end.min(text.len())]),
+            (Some(_), Some((start, _))) => Err(passthrough(start)),
+            (Some(_), None) => Err(passthrough(text.len())),
+        };
+        // Every line of the input may have its own prefix, so segments never go past a line break
+        let selected = match &args.line_prefix {
+            Some(_) => selected.map(|selected| selected.find('\n').map_or(selected, |pos| &selected[..pos + 1])),
+            None => selected,
+        };
+
+        // Every line has its own prefix, an empty one tells the printer that the line has none
+        let prefix = args.line_prefix.as_ref()
+            .filter(|_| at_line_start)
+            .map(|prefix| prefix.find(text).filter(|span| span.start() == 0).map_or(0, |span| span.end()));
+
+        let found = explainer.as_mut().map(|explainer| explainer.begin(&stack));
+        let (bytes_to_consume, kind) = match (prefix, selected) {
+            (Some(len), _) => (len, SegmentKind::Prefix(args.prefix_style)),
+            (None, Ok(selected)) => single_iteration(&args, selected, preceding, &mut stack, found),
+            (None, Err(len)) => (len, SegmentKind::RecordEnd),
+        };
+
+        // Line starts after a line break, or after a record separator inside of the selected spans
+        at_line_start = text[..bytes_to_consume].ends_with('\n')
+            || (kind == SegmentKind::RecordEnd && selected.is_ok());
+
+        if let Some((start, end)) = &mut selection {
+            *start = start.saturating_sub(bytes_to_consume);
+            *end = end.saturating_sub(bytes_to_consume);
+            if *end == 0 {
+                // Selected span is formatted on its own
+                selection = None;
+                stack.clear();
+            }
+        }
+        preceding = text[..bytes_to_consume].chars().next_back().or(preceding);
 
-        let mut buf = [0u8; 1024];
+        // Segment is pushed to the printer as a whole, so blocks are opened and closed once
+        segment.resize(bytes_to_consume, 0);
         let mut total_consumed = 0;
         while total_consumed < bytes_to_consume {
-            let to_read = bytes_to_consume.min(buf.len());
-            let was_read = reader.read(&mut buf[..to_read]).map_err(|x| x.to_string())?;
+            let was_read = reader.read(&mut segment[total_consumed..]).map_err(|x| x.to_string())?;
             if was_read == 0 {
                 should_stop = true;
                 break;
             }
             total_consumed += was_read;
+        }
 
-            printer.push_segment(
-                &buf[..was_read], 
-                indent_level, 
-                is_literal,
-                is_closing
-            ).map_err(|x| x.to_string())?;
+        if let Some(explainer) = &mut explainer {
+            explainer.step(printer.writer(), &args, &stack, kind, &segment[..total_consumed])
+                .map_err(|x| x.to_string())?;
+        } else if args.debug_view {
+            write_debug_segment(printer.writer(), &args, &stack, kind, &segment[..total_consumed]).map_err(|x| x.to_string())?;
+        } else {
+            printer.push_segment(&segment[..total_consumed], kind).map_err(|x| x.to_string())?;
+        }
 
-            // write_escaped_newlines(&mut writer, &buf[..was_read]).map_err(|x| x.to_string())?;
-            // writer.write("\n".as_bytes()).map_err(|x| x.to_string())?;
+        if args.stream && kind == SegmentKind::RecordEnd {
+            printer.flush().map_err(|x| x.to_string())?;
         }
-        printer.writer().flush().unwrap();
-    }   
-    
+    }
+
+    if let Some(explainer) = explainer {
+        explainer.finish(printer.writer(), &args).map_err(|x| x.to_string())?;
+    }
+    printer.finish().map_err(|x| x.to_string())?;
+    printer.writer().finish().map_err(|x| x.to_string())?;
     Ok(())
 }
 
-/// Returns count of bytes that should be consumed
+/// Pair that is currently open.
+struct Frame {
+    /// Index of the pair in `ArgsStructured::pairs`
+    index: usize,
+    /// Closing pattern, resolved against the text the pair was opened with
+    closing: Pattern,
+    /// Block has only whitespace up to its closing, which was already found in the window
+    is_empty: bool,
+}
+
+/// Finds the next non-empty span to format. Only the `select` group is taken if the regex has one.
+fn find_selection(select: &Regex, text: &str) -> Option<(usize, usize)> {
+    let has_group = select.capture_names().any(|name| name == Some("select"));
+
+    select.captures_iter(text)
+        .filter_map(|captures| match has_group {
+            true => captures.name("select"),
+            false => captures.get(0),
+        })
+        .map(|span| (span.start(), span.end()))
+        .find(|(start, end)| start < end)
+}
+
+/// Records where a pattern was found, if the caller wants to know (`--explain`)
+fn note(found: &mut Option<&mut Vec<Found>>, candidate: Candidate, pos: Option<(usize, usize)>) {
+    if let (Some(found), Some((start, end))) = (found.as_deref_mut(), pos) {
+        found.push(Found { candidate, start, end });
+    }
+}
+
+/// Whether the block that was opened with `opening` has only whitespace up to its `closing`, within the window.
+/// Keywords are never joined together (`begin end`), so such blocks are left as is.
+fn is_empty_block(opening: &str, rest: &str, closing: &Pattern) -> bool {
+    let inner = rest.len() - rest.trim_start().len();
+    if inner == 0 {
+        return false;
+    }
+
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    if is_word(opening.chars().next_back()) && is_word(rest[inner..].chars().next()) {
+        return false;
+    }
+
+    closing.find_in(&rest[inner..], rest[..inner].chars().next_back())
+        .is_some_and(|(start, end)| start == 0 && start < end)
+}
+
+/// Whether some literal opening is found at the start of `text` and is longer than `len` bytes.
+fn has_longer_literal_opening(args: &ArgsStructured, text: &str, preceding: Option<char>, len: usize) -> bool {
+    args.pairs.iter()
+        .filter(|pair| pair.role.is_literal())
+        .any(|pair| matches!(pair.opening.find_in(text, preceding), Some((0, end)) if end > len))
+}
+
+/// Returns count of bytes that should be consumed and what they are
 fn single_iteration(
-    args: &ArgsStructured, text: &str, window_size: usize, 
-    literal_stack: &mut Vec<usize>, patterns_stack: &mut Vec<usize>
-) -> (usize, bool) {
+    args: &ArgsStructured, text: &str, preceding: Option<char>,
+    stack: &mut Vec<Frame>, mut found: Option<&mut Vec<Found>>
+) -> (usize, SegmentKind) {
     // print!("Text is {}:", text.len());
-    // write_escaped_newlines(&mut stdout(), text.as_bytes()).unwrap();
+    // write_escaped_whitespace(&mut stdout(), text.as_bytes()).unwrap();
     // print!("\n");
-    let last_literal = literal_stack.last().cloned();
-    let last_pattern = patterns_stack.last().cloned();
-
     let mut max_possible_jump_bytes = text.len();
-
-    if let Some(literal_idx) = last_literal {
-        // Check for current literal closing
-        let closing_literal = &args.literals[literal_idx].1;
-        let pos = closing_literal.find_in(text);
+    let top_role = stack.last().map(|frame| args.pairs[frame.index].role);
+
+    // Everything inside of a string or a comment (including interpolations) is printed as is
+    let is_in_literal = stack.iter().any(|frame| args.pairs[frame.index].role.is_literal());
+    let current_kind = match stack.last() {
+        Some(frame) if is_in_literal => SegmentKind::Literal(frame.index),
+        _ => SegmentKind::Text,
+    };
+
+    // Check for the end of a record, that resets everything
+    if let Some(separator) = &args.record_separator {
+        let pos = separator.find_in(text, preceding).filter(|(start, end)| start < end);
+        note(&mut found, Candidate::RecordSeparator, pos);
         merge_min(&mut max_possible_jump_bytes, pos);
 
         if let Some((0, end)) = pos {
-            // Literal closed
-            literal_stack.pop();
-            return (end, false);
+            stack.clear();
+            return (end, SegmentKind::RecordEnd);
         }
+    }
 
-    } else if let Some(pattern_idx) = last_pattern {
-        // Check for current pattern closing
-        let closing_pattern = &args.patterns[pattern_idx].1;
-        let pos = closing_pattern.find_in(text);
+    // Check for escape sequences, which mean nothing inside of comments
+    if !args.disallow_escaping && !args.escape_sequence.is_empty() && top_role != Some(Role::Comment) {
+        if let Some(escaped) = text.strip_prefix(args.escape_sequence.as_str()) {
+            // Character after the escape sequence is never a part of a pattern
+            let len = args.escape_sequence.len() + escaped.chars().next().map_or(0, char::len_utf8);
+            note(&mut found, Candidate::Escape, Some((0, len)));
+            return (len, current_kind);
+        }
+
+        let pos = text.find(args.escape_sequence.as_str())
+            .map(|pos| (pos, pos + args.escape_sequence.len()));
+        note(&mut found, Candidate::Escape, pos);
         merge_min(&mut max_possible_jump_bytes, pos);
+    }
 
-        if let Some((0, end)) = pos {
-            // Pattern closed
-            patterns_stack.pop();
-            return (end, true);
+    if let Some(frame) = stack.last() {
+        // Check for current pair closing
+        let pos = frame.closing.find_in(text, preceding);
+        note(&mut found, Candidate::Closing(frame.index), pos);
+
+        // Whitespace of an empty block is consumed along with its closing, unless something else comes first
+        let is_empty = frame.is_empty && pos.is_some_and(|(start, _)| {
+            start <= max_possible_jump_bytes && text[..start].trim_start().is_empty()
+        });
+        merge_min(&mut max_possible_jump_bytes, pos);
+
+        if let Some((_, end)) = pos.filter(|(start, _)| *start == 0 || is_empty) {
+            // Pair closed
+            let index = frame.index;
+            stack.pop();
+
+            let kind = if is_in_literal { SegmentKind::Literal(index) } else { SegmentKind::Closing };
+            return (end, kind);
+        }
+    }
+
+    // Closing of an outer pair closes the inner ones one by one, without consuming anything
+    if args.close_unclosed && !is_in_literal && stack.len() > 1 {
+        for frame in stack[..stack.len() - 1].iter().rev() {
+            let pos = frame.closing.find_in(text, preceding);
+            note(&mut found, Candidate::Closing(frame.index), pos);
+            merge_min(&mut max_possible_jump_bytes, pos);
+
+            if let Some((0, _)) = pos {
+                stack.pop();
+                return (0, SegmentKind::Closing);
+            }
+        }
+    }
+
+    let literals = !matches!(top_role, Some(Role::String | Role::Comment));
+
+    // Inside of a string only interpolations are looked for, inside of a comment - nothing
+    let (opened_roles, delimiters): (&[Role], _) = match top_role {
+        Some(Role::String) => (&[Role::Interpolation], false),
+        Some(Role::Comment) => (&[], false),
+        Some(Role::Bracket) => (&[Role::Bracket], !is_in_literal),
+        Some(Role::Interpolation) | None => (&[Role::Bracket], false),
+    };
+
+    // Check for brackets opening
+    for (idx, pair) in args.pairs.iter().enumerate() {
+        if !opened_roles.contains(&pair.role) {
+            continue;
         }
 
+        let pos = pair.opening.find_in(text, preceding);
+        note(&mut found, Candidate::Opening(idx), pos);
+        merge_min(&mut max_possible_jump_bytes, pos);
+
+        if let Some((0, end)) = pos {
+            // Longer literal opening at the same place wins, like Lua `[==[` over `[`
+            if literals && has_longer_literal_opening(args, text, preceding, end) {
+                continue;
+            }
+
+            // Element that never has a closing
+            if pair.opening.is_void(text) {
+                return (end, current_kind);
+            }
+
+            // Pair opened
+            let closing = pair.closing.resolve_closing(&pair.opening, text);
+            let is_empty = !is_in_literal && !args.reindent && !args.keep_line_breaks
+                && is_empty_block(&text[..end], &text[end..], &closing);
+            stack.push(Frame { index: idx, closing, is_empty });
+
+            let kind = if is_in_literal { SegmentKind::Literal(idx) } else { SegmentKind::Opening(idx, pair.style) };
+            return (end, kind);
+        }
     }
 
-    // Check for patterns opening
-    if literal_stack.is_empty() {
-        for (idx, (opening_pattern, _)) in args.patterns.iter().enumerate() {
-            let pos = opening_pattern.find_in(text);
+    // Check for delimiters (only inside of blocks)
+    if delimiters {
+        for (idx, (delimiter, style)) in args.delimiters.iter().enumerate() {
+            let pos = delimiter.find_in(text, preceding);
+            note(&mut found, Candidate::Delimiter(idx), pos);
             merge_min(&mut max_possible_jump_bytes, pos);
 
             if let Some((0, end)) = pos {
-                // Pattern opened
-                patterns_stack.push(idx);
-                return (end, false);
+                return (end, SegmentKind::Delimiter(*style));
             }
-
         }
     }
 
     // Check for literals opening
-    for (idx, (opening_literal, _)) in args.literals.iter().enumerate() {
-        let pos = opening_literal.find_in(text);
-        merge_min(&mut max_possible_jump_bytes, pos);
+    if literals {
+        for (idx, pair) in args.pairs.iter().enumerate() {
+            if !pair.role.is_literal() {
+                continue;
+            }
 
-        if let Some((0, end)) = pos {
-            // Literal opened
-            literal_stack.push(idx);
-            return (end, false);
+            let pos = pair.opening.find_in(text, preceding);
+            note(&mut found, Candidate::Opening(idx), pos);
+            merge_min(&mut max_possible_jump_bytes, pos);
+
+            if let Some((0, end)) = pos {
+                // Literal opened
+                let closing = pair.closing.resolve_closing(&pair.opening, text);
+                stack.push(Frame { index: idx, closing, is_empty: false });
+                return (end, SegmentKind::Literal(idx));
+            }
         }
+    }
 
+    // Text cut by the end of the window is spaced in pieces, so it is not cut inside of an operator
+    if max_possible_jump_bytes == text.len() && current_kind == SegmentKind::Text && !args.spacing.is_empty() {
+        if let Some(cut) = Spacing::last_cut(text) {
+            max_possible_jump_bytes = cut;
+        }
     }
 
     // println!("Max possible jump is: {}", max_possible_jump_bytes);
-    return (max_possible_jump_bytes, false);
+    (max_possible_jump_bytes, current_kind)
+}
+
+/// Writes a segment on its own line, along with what the scanner found it to be and the stack after it.
+fn write_debug_segment(
+    writer: &mut impl Write, args: &ArgsStructured,
+    stack: &[Frame], kind: SegmentKind, segment: &[u8]
+) -> std::io::Result<()> {
+    let name = |index: usize| args.pairs[index].name.escape_debug().to_string();
+
+    let label = match kind {
+        SegmentKind::Text => "text".to_owned(),
+        SegmentKind::Literal(index) => format!("literal {}", name(index)),
+        SegmentKind::Opening(index, _) => format!("open {}", name(index)),
+        SegmentKind::Closing => "close".to_owned(),
+        SegmentKind::Delimiter(_) => "delimiter".to_owned(),
+        SegmentKind::RecordEnd => "record end".to_owned(),
+        SegmentKind::Prefix(_) => "prefix".to_owned(),
+    };
+    let stack_names = stack.iter()
+        .map(|frame| name(frame.index))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let header = format!(" {:>5} {:<16} [{}] ", segment.len(), label, stack_names).black();
+    let header = if stack.iter().any(|frame| args.pairs[frame.index].role.is_literal()) {
+        header.on_blue()
+    } else if !stack.is_empty() {
+        header.on_yellow()
+    } else {
+        header.on_bright_white()
+    };
+
+    write!(writer, "{} ", header)?;
+    write_escaped_whitespace(writer, segment)?;
+    writer.write_all("\n".as_bytes())
 }
 
-fn write_escaped_newlines(writer: &mut impl Write, data: &[u8]) -> std::io::Result<usize> {
-    let newline = "↲".blue().to_string();
-    let mut was_written = 0;
+fn write_escaped_whitespace(writer: &mut impl Write, data: &[u8]) -> std::io::Result<()> {
+    let mut rest = data;
+
+    while let Some(pos) = rest.iter().position(|x| matches!(x, b'\n' | b'\t' | b'\r')) {
+        writer.write_all(&rest[..pos])?;
+
+        let symbol = match rest[pos] {
+            b'\n' => "↲",
+            b'\t' => "→",
+            _ => "␍",
+        };
+        write!(writer, "{}", symbol.blue())?;
+
+        rest = &rest[pos + 1..];
+    }
 
-    let mut is_first = true; 
-    for part in data.split(|x| *x == '\n'.as_ascii().unwrap().to_u8()) {
-        if !is_first {
-            was_written += writer.write(newline.as_bytes())?;
+    writer.write_all(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn args(argv: &[&str]) -> ArgsStructured {
+        let args = cli::Args::try_parse_from(std::iter::once("format-brackets").chain(argv.iter().copied())).unwrap();
+        cli::structure(args).unwrap()
+    }
+
+    /// Splits the whole `text` into segments the way the main loop does.
+    fn scan<'a>(args: &ArgsStructured, text: &'a str) -> Vec<(&'a str, SegmentKind)> {
+        let mut stack = vec![];
+        let mut segments = vec![];
+        let mut rest = text;
+        let mut preceding = None;
+        while !rest.is_empty() {
+            let (len, kind) = single_iteration(args, rest, preceding, &mut stack, None);
+            if len > 0 {
+                preceding = rest[..len].chars().next_back();
+            }
+            segments.push((&rest[..len], kind));
+            rest = &rest[len..];
         }
-        is_first = false;
-        
-        was_written += writer.write(part)?;
+        segments
     }
 
-    Ok(was_written)
-}
\ No newline at end of file
+    #[test]
+    fn escapes_mean_nothing_in_comments() {
+        let args = args(&[]);
+        let segments = scan(&args, "x  # C:\\\ny(a)");
+        assert!(segments.contains(&("y", SegmentKind::Text)), "{segments:?}");
+        assert!(segments.iter().any(|&(text, kind)| text == "(" && matches!(kind, SegmentKind::Opening(..))), "{segments:?}");
+    }
+
+    #[test]
+    fn spaced_text_is_not_cut_inside_of_an_operator() {
+        let args = args(&["--normalize-spacing"]);
+        // Window ends in the middle of `x==y`
+        assert_eq!(single_iteration(&args, "aaa x=", None, &mut vec![], None), (3, SegmentKind::Text));
+    }
+
+    fn plain(text: &str) -> Pattern {
+        Pattern::PlainText(text.to_owned())
+    }
+
+    #[test]
+    fn whitespace_only_blocks_are_empty() {
+        assert!(is_empty_block("(", " \n\t)x", &plain(")")));
+        assert!(!is_empty_block("(", " a)", &plain(")")));
+        // Block without whitespace is already as short as it gets
+        assert!(!is_empty_block("(", ")", &plain(")")));
+    }
+
+    #[test]
+    fn keyword_blocks_are_never_empty() {
+        assert!(!is_empty_block("begin", " end", &Pattern::Keyword("end".to_owned())));
+        assert!(!is_empty_block("do", "\n  end", &Pattern::Keyword("end".to_owned())));
+    }
+
+    #[test]
+    fn closing_has_to_be_in_the_window() {
+        // Window ends before the closing is seen
+        assert!(!is_empty_block("{", "   ", &plain("}")));
+        assert!(!is_empty_block("{", "  \n", &plain("}")));
+    }
+}
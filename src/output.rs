@@ -0,0 +1,159 @@
+use std::{io::Write, sync::{Arc, OnceLock}};
+
+use crate::cli::LineEnding;
+
+/// Writer that normalizes line breaks of the output and how it ends.
+pub struct Normalizer<W: Write> {
+    inner: W,
+    /// Every line break is written as this
+    line_ending: LineEnding,
+    /// Line ending of the input, for `LineEnding::Auto`
+    detected: Arc<OnceLock<LineEnding>>,
+    /// Output ends with exactly one line break, unless it is empty
+    final_newline: bool,
+    /// Line breaks after the first one at the end of what was written so far, held back by `final_newline`
+    held: Vec<u8>,
+    /// What was written so far ends with a line break
+    ends_with_break: bool,
+    /// `\r` at the end of what was written so far, that may be a part of `\r\n`
+    held_cr: bool,
+    has_content: bool,
+}
+
+impl<W: Write> Normalizer<W> {
+    pub fn new(inner: W, line_ending: LineEnding, detected: Arc<OnceLock<LineEnding>>, final_newline: bool) -> Self {
+        Self {
+            inner, line_ending, detected, final_newline,
+            held: Vec::new(), ends_with_break: false, held_cr: false, has_content: false,
+        }
+    }
+
+    fn resolved_line_ending(&self) -> LineEnding {
+        match self.line_ending {
+            LineEnding::Auto => self.detected.get().copied().unwrap_or(LineEnding::Lf),
+            line_ending => line_ending,
+        }
+    }
+
+    /// Writes what was held back. Should be called once the output has ended.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        let mut out = Vec::new();
+        if std::mem::take(&mut self.held_cr) {
+            self.content(&mut out, b"\r");
+        }
+
+        if self.final_newline && self.has_content && !self.ends_with_break {
+            out.extend_from_slice(self.resolved_line_ending().as_str().as_bytes());
+        }
+        self.held.clear();
+
+        self.inner.write_all(&out)?;
+        self.inner.flush()
+    }
+
+    /// The first line break after content is written right away, so that streamed lines are not delayed.
+    /// With `final_newline` the ones after it are held until more content shows that they are not at the end.
+    fn line_break(&mut self, out: &mut Vec<u8>) {
+        let line_break = self.resolved_line_ending().as_str();
+        match self.final_newline && (self.ends_with_break || !self.has_content) {
+            true => self.held.extend_from_slice(line_break.as_bytes()),
+            false => out.extend_from_slice(line_break.as_bytes()),
+        }
+        self.ends_with_break = true;
+    }
+
+    fn content(&mut self, out: &mut Vec<u8>, bytes: &[u8]) {
+        out.append(&mut self.held);
+        self.ends_with_break = false;
+        out.extend_from_slice(bytes);
+        self.has_content = true;
+    }
+}
+
+impl<W: Write> Write for Normalizer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.resolved_line_ending() == LineEnding::Lf && !self.final_newline {
+            return self.inner.write(buf);
+        }
+
+        let mut out = Vec::with_capacity(buf.len());
+        for (pos, &byte) in buf.iter().enumerate() {
+            if std::mem::take(&mut self.held_cr) {
+                if byte == b'\n' {
+                    self.line_break(&mut out);
+                    continue;
+                }
+                self.content(&mut out, b"\r");
+            }
+
+            match byte {
+                b'\r' => self.held_cr = true,
+                b'\n' => self.line_break(&mut out),
+                _ => {
+                    // Everything up to the next line break is copied at once
+                    let len = buf[pos..].iter().position(|x| matches!(x, b'\r' | b'\n')).unwrap_or(buf.len() - pos);
+                    self.content(&mut out, &buf[pos..pos + len]);
+                    return self.inner.write_all(&out).map(|()| pos + len);
+                },
+            }
+        }
+
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(line_ending: LineEnding, final_newline: bool, writes: &[&str]) -> String {
+        let detected = Arc::new(OnceLock::new());
+        let mut normalizer = Normalizer::new(Vec::new(), line_ending, detected, final_newline);
+        for text in writes {
+            normalizer.write_all(text.as_bytes()).unwrap();
+        }
+        normalizer.finish().unwrap();
+        String::from_utf8(normalizer.inner).unwrap()
+    }
+
+    #[test]
+    fn final_newline_is_added() {
+        assert_eq!(normalize(LineEnding::Auto, true, &["a"]), "a\n");
+    }
+
+    #[test]
+    fn final_newline_drops_trailing_blank_lines() {
+        assert_eq!(normalize(LineEnding::Auto, true, &["a\n\n", "\n"]), "a\n");
+        assert_eq!(normalize(LineEnding::Auto, true, &["a\n\n", "b\n"]), "a\n\nb\n");
+        assert_eq!(normalize(LineEnding::Auto, true, &["\n\n"]), "");
+    }
+
+    #[test]
+    fn first_line_break_is_not_delayed() {
+        let detected = Arc::new(OnceLock::new());
+        let mut normalizer = Normalizer::new(Vec::new(), LineEnding::Auto, detected, true);
+        normalizer.write_all(b"a\n\n").unwrap();
+        assert_eq!(normalizer.inner, b"a\n");
+    }
+
+    #[test]
+    fn line_breaks_are_converted() {
+        assert_eq!(normalize(LineEnding::Crlf, false, &["a\nb\n"]), "a\r\nb\r\n");
+        assert_eq!(normalize(LineEnding::Cr, false, &["a\r\nb"]), "a\rb");
+        assert_eq!(normalize(LineEnding::Lf, true, &["a\r", "\nb\rc"]), "a\nb\rc\n");
+    }
+
+    #[test]
+    fn auto_follows_the_input() {
+        let detected = Arc::new(OnceLock::new());
+        detected.set(LineEnding::Crlf).unwrap();
+        let mut normalizer = Normalizer::new(Vec::new(), LineEnding::Auto, detected, false);
+        normalizer.write_all(b"a\nb").unwrap();
+        assert_eq!(normalizer.inner, b"a\r\nb");
+    }
+}
@@ -1,88 +1,845 @@
+//! Streaming pretty printer.
+//!
+//! Segments of the scanner are printed as they come, except for groups: a pair that is not `inline`
+//! is held back while it is built into a document (see [`crate::doc`]): whitespace becomes a `line`,
+//! line breaks of the input a `hardline`, and the block of the pair a nest between two `softline`s.
+//! The group is printed flat if it fits together with the text attached to its closing, like `;` or `,`.
+//! Otherwise the block is broken according to its style, and the groups inside of it are considered one by one.
+//!
+//! Like in Oppen's algorithm the lookahead is bounded: segments are held back only until the group
+//! closes or gets wider than the space that is left for it, whichever happens first.
+
 use std::io::Write;
 
+use colored::{Color, ColoredString, Colorize};
+use unicode_width::UnicodeWidthChar;
+
+use crate::{cli::{BlockStyle, DelimiterStyle, PrefixStyle}, doc::{Builder, Doc, Flat}, spacing::Spacing};
+
+/// What the scanner found a segment to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    /// Plain text outside of literals
+    Text,
+    /// Opening, contents or closing of the literal with the given index - printed as is
+    Literal(usize),
+    /// Opening pattern of the pair with the given index, its block is formatted with the given style
+    Opening(usize, BlockStyle),
+    /// Closing pattern of the innermost block
+    Closing,
+    /// Delimiter between elements of a block
+    Delimiter(DelimiterStyle),
+    /// Separator between independent records or text outside of `--select`ed spans.
+    /// Closes all blocks and is printed as is
+    RecordEnd,
+    /// Prefix at the start of a record, repeated on every line the record is broken into
+    Prefix(PrefixStyle),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Paint {
+    Color(Color),
+    Dimmed,
+}
+
+impl Paint {
+    fn apply(self, text: &str) -> ColoredString {
+        match self {
+            Paint::Color(color) => text.color(color),
+            Paint::Dimmed => text.dimmed(),
+        }
+    }
+}
+
+/// Width of a tab in columns, as in terminals.
+const TAB_WIDTH: usize = 8;
+
+/// Colours of the output.
+#[derive(Debug, Clone)]
+pub struct Palette {
+    /// Cycled through by the depth of blocks
+    pub brackets: Vec<Paint>,
+    /// Paint of every pair, by its index. Brackets without one are coloured by depth
+    pub pairs: Vec<Option<Paint>>,
+}
+
+/// Indentation of a line: a tab for every level, then spaces that align it to a column.
+/// Kept as counts, so that deeply nested blocks do not hold a string each.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Indent {
+    tabs: usize,
+    spaces: usize,
+}
+
+impl Indent {
+    /// One level deeper
+    fn nested(self) -> Self {
+        Self { tabs: self.tabs + 1, ..self }
+    }
+
+    /// Aligned to the given column after this indentation
+    fn aligned(self, column: usize) -> Self {
+        Self { spaces: self.spaces + column, ..self }
+    }
+
+    fn width(self) -> usize {
+        self.tabs * TAB_WIDTH + self.spaces
+    }
+}
+
+/// Block opened in the output.
+struct Block {
+    style: BlockStyle,
+    /// Written at the start of every line inside of the block
+    indent: Indent,
+    /// Indentation of the enclosing block, used for the closing pattern
+    outer_indent: Indent,
+    /// Whether anything was written inside of the block yet
+    started: bool,
+    /// Count of element separators written inside of an S-expression block
+    separators: usize,
+    /// Paint of the opening pattern, used for the closing one as well
+    paint: Option<Paint>,
+}
+
+/// How the output is laid out.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    /// Groups up to this wide are printed on a single line, unless there is `max_width`
+    pub min_block_size: usize,
+    /// Lines longer than this are wrapped at whitespace
+    pub max_width: Option<usize>,
+    /// Group is printed broken once the lookahead holds more bytes than this, whether it fits or not
+    pub max_lookahead: usize,
+    /// Line breaks of the input are never removed, but new ones may be added
+    pub keep_line_breaks: bool,
+    /// Longest run of blank lines that is kept
+    pub max_blank_lines: Option<usize>,
+    /// Only indentation of the lines of the input is changed
+    pub reindent_only: bool,
+    /// Whitespace is dropped at the end of lines
+    pub trim_trailing_whitespace: bool,
+    /// Spacing of operators in the text outside of literals
+    pub spacing: Spacing,
+}
+
 pub struct Printer<P: Write> {
-    min_block_size: usize,
+    layout: Layout,
     was_literal: bool,
     is_at_line_start: bool,
-    prev_indent: usize,
+    /// Line break requested by a block, written right before the next visible text
+    pending_break: bool,
+    /// Whitespace between elements of an S-expression, laid out right before the next element
+    pending_separator: bool,
+    /// Single space requested by a delimiter, written right before the next visible text
+    pending_space: bool,
+    /// Trailing whitespace of the text, written only if something follows it on the same line
+    pending_whitespace: String,
+    /// Indentation the current line was started with
+    line_indent: Indent,
+    /// Display width of the text written on the current line after `line_indent`
+    column: usize,
+    /// Whether the current line was wrapped by `max_width`
+    is_wrapped: bool,
+    /// Count of blank lines right before the cursor
+    blank_lines: usize,
+    blocks: Vec<Block>,
+    /// Segments of a group that is not yet known to fit
+    lookahead: Vec<(Vec<u8>, SegmentKind)>,
+    /// Document of the segments in `lookahead`
+    group: Builder<(Vec<u8>, SegmentKind)>,
+    /// Width the group in `lookahead` has to fit in
+    lookahead_budget: usize,
+    /// Count of bytes held in `lookahead`
+    lookahead_bytes: usize,
+    /// Group in `lookahead` is closed and fits, unless the text attached to its closing does not
+    is_group_closed: bool,
+    /// Start of a literal, held until the width of its first line is known (with `max_width`)
+    literal: Vec<(Vec<u8>, SegmentKind)>,
+    /// Written at the start of every line the current line of the input is broken into, before the indentation
+    prefix: Option<String>,
+    /// Line was broken, but its prefix is written only once something is printed on it
+    is_prefix_pending: bool,
+    palette: Option<Palette>,
+    /// Paint of the segment being printed
+    paint: Option<Paint>,
     output: P,
 }
 
 impl<P: Write> Printer<P> {
-    pub fn new(min_block_size: usize, output: P) -> Self {
-        Self { 
-            min_block_size,
+    pub fn new(layout: Layout, palette: Option<Palette>, output: P) -> Self {
+        Self {
+            layout,
             was_literal: false,
             is_at_line_start: true,
-            prev_indent: 0,
+            pending_break: false,
+            pending_separator: false,
+            pending_space: false,
+            pending_whitespace: String::new(),
+            line_indent: Indent::default(),
+            column: 0,
+            is_wrapped: false,
+            blank_lines: 0,
+            blocks: Vec::new(),
+            lookahead: Vec::new(),
+            group: Builder::default(),
+            lookahead_budget: 0,
+            lookahead_bytes: 0,
+            is_group_closed: false,
+            literal: Vec::new(),
+            prefix: None,
+            is_prefix_pending: false,
+            palette,
+            paint: None,
             output
         }
     }
 
-    pub fn push_segment(&mut self, segment: &[u8], indentation_level: usize, is_literal: bool, is_closing: bool) -> std::io::Result<()> {
-        self.push_naive(segment, indentation_level, is_literal, is_closing)
+    pub fn push_segment(&mut self, segment: &[u8], kind: SegmentKind) -> std::io::Result<()> {
+        // Empty closing is a pair that was left open and is closed along with an outer one
+        // Empty prefix is a line of the input that has none
+        if segment.is_empty() && !matches!(kind, SegmentKind::Closing | SegmentKind::Prefix(_)) {
+            return Ok(());
+        }
+        if !matches!(kind, SegmentKind::Literal(_)) {
+            self.flush_literal()?;
+        }
+        if self.is_group_closed {
+            self.settle_group(attached_width(segment, kind))?;
+        }
+
+        if matches!(kind, SegmentKind::RecordEnd | SegmentKind::Prefix(_)) {
+            while !self.lookahead.is_empty() {
+                self.break_lookahead()?;
+            }
+            return self.print_segment(segment, kind);
+        }
+
+        let kind = match kind {
+            _ if !self.layout.reindent_only => kind,
+            // Blocks never break lines and delimiters are plain text
+            SegmentKind::Opening(index, BlockStyle::Hang | BlockStyle::Lisp) => SegmentKind::Opening(index, BlockStyle::Hang),
+            SegmentKind::Opening(index, _) => SegmentKind::Opening(index, BlockStyle::Inline),
+            SegmentKind::Delimiter(_) => SegmentKind::Text,
+            kind => kind,
+        };
+
+        let spaced;
+        let segment = match kind {
+            SegmentKind::Text if !self.layout.spacing.is_empty() => {
+                spaced = self.layout.spacing.apply(unsafe { str::from_utf8_unchecked(segment) });
+                spaced.as_bytes()
+            },
+            // Closing of an empty block comes with the whitespace inside of it
+            SegmentKind::Closing => segment.trim_ascii_start(),
+            _ => segment,
+        };
+
+        let is_group = !self.layout.reindent_only
+            && matches!(kind, SegmentKind::Opening(_, style) if style != BlockStyle::Inline);
+        if !self.lookahead.is_empty() || is_group {
+            return self.push_lookahead(segment, kind);
+        }
+
+        let is_literal_start = !self.literal.is_empty() || !self.was_literal;
+        if matches!(kind, SegmentKind::Literal(_)) && is_literal_start && self.layout.max_width.is_some() {
+            return self.hold_literal(segment, kind);
+        }
+
+        self.print_segment(segment, kind)
+    }
+
+    /// Shows what is known to be printed while waiting for more input.
+    /// A closed group is printed as it is, without waiting for the text attached to its closing.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if self.is_group_closed {
+            self.settle_group(0)?;
+        }
+        self.output.flush()
     }
 
-    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
-        const NEWLINE: u8 = '\n'.as_ascii().unwrap().to_u8();
+    /// Prints everything that is still held back. Should be called once the input has ended.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        if self.is_group_closed {
+            self.settle_group(0)?;
+        }
+        while !self.lookahead.is_empty() {
+            self.break_lookahead()?;
+        }
+        self.flush_literal()?;
 
-        self.output.write_all(bytes)?;
-        self.is_at_line_start = bytes.last().cloned() == Some(NEWLINE);
-        Ok(())
+        if self.layout.trim_trailing_whitespace {
+            self.pending_whitespace.clear();
+        }
+        self.flush_whitespace()?;
+        self.output.flush()
     }
 
-    fn push_naive(&mut self, segment: &[u8], indentation_level: usize, is_literal: bool, is_closing: bool) -> std::io::Result<()> {
-        if segment.is_empty() {
-            return Ok(());
+    fn push_lookahead(&mut self, segment: &[u8], kind: SegmentKind) -> std::io::Result<()> {
+        if self.lookahead.is_empty() {
+            self.lookahead_budget = self.fit_budget();
         }
-        
-        if is_literal {
-            if self.is_at_line_start && !self.was_literal {
-                for _ in 0..indentation_level {
-                    self.write("\t".as_bytes())?;
+
+        self.lookahead.push((segment.to_vec(), kind));
+        self.lookahead_bytes += segment.len();
+        self.build_group(segment, kind);
+
+        // Memory is bounded even if the group collapses into little width (like a lot of whitespace)
+        let is_over_budget = self.group.width() > self.lookahead_budget || self.lookahead_bytes > self.layout.max_lookahead;
+
+        if self.group.is_broken() || is_over_budget {
+            self.break_lookahead()
+        } else {
+            // Whether it fits is known once the next segment shows what is attached to the closing
+            self.is_group_closed = self.group.depth() == 0;
+            Ok(())
+        }
+    }
+
+    /// Adds a segment of the lookahead to the document of its group.
+    fn build_group(&mut self, segment: &[u8], kind: SegmentKind) {
+        let text = unsafe { str::from_utf8_unchecked(segment) };
+        let width = display_width(text);
+
+        // S-expressions are free-form, in other blocks line breaks of the input are kept
+        let is_free_form = !self.layout.keep_line_breaks
+            && matches!(self.lookahead.first(), Some((_, SegmentKind::Opening(_, BlockStyle::Lisp))));
+
+        match kind {
+            SegmentKind::Opening(..) => self.group.open((segment.to_vec(), kind), width),
+            SegmentKind::Closing => self.group.close((segment.to_vec(), kind), width),
+            SegmentKind::Delimiter(_) => {
+                // Delimiter is attached to the preceding element and followed by a single space
+                self.group.drop_line();
+                self.group.text((segment.to_vec(), kind), width);
+                self.group.line();
+            },
+            SegmentKind::Literal(_) => {
+                self.group.text((segment.to_vec(), kind), width);
+                if text.contains('\n') {
+                    self.group.hard_line();
                 }
-            }
+            },
+            SegmentKind::Text => for (is_whitespace, run) in whitespace_runs(text) {
+                match is_whitespace {
+                    true if run.contains('\n') && !is_free_form => self.group.hard_line(),
+                    true => self.group.line(),
+                    false => self.group.text((run.as_bytes().to_vec(), kind), display_width(run)),
+                }
+            },
+            SegmentKind::RecordEnd | SegmentKind::Prefix(_) => {},
+        }
+    }
+
+    /// Prints the closed group in `lookahead` flat if it fits along with `attached` columns after it.
+    /// Without `max_width` only the group itself has to fit in `min_block_size`.
+    fn settle_group(&mut self, attached: usize) -> std::io::Result<()> {
+        self.is_group_closed = false;
+        let attached = if self.layout.max_width.is_some() { attached } else { 0 };
+        if self.group.width() + attached > self.lookahead_budget {
+            return self.break_lookahead();
+        }
 
-            self.write(segment)?;
+        self.lookahead.clear();
+        self.lookahead_bytes = 0;
+        let docs = std::mem::take(&mut self.group).finish();
+        self.print_flat(&docs)
+    }
+
+    /// Width a group starting at the current position may take to be printed flat:
+    /// the rest of the line with `max_width`, otherwise `min_block_size`.
+    fn fit_budget(&self) -> usize {
+        let Some(max_width) = self.layout.max_width else {
+            return self.layout.min_block_size;
+        };
+
+        let used = if self.column == 0 || self.pending_break {
+            self.current_indent().width()
         } else {
-            let newline: u8 = '\n'.as_ascii().unwrap().to_u8();
+            self.line_indent.width() + self.column + display_width(&self.pending_whitespace)
+        };
+        let prefix_width = self.prefix.as_deref().map_or(0, display_width);
 
+        max_width.saturating_sub(used + prefix_width)
+    }
 
-            let mut is_first_line = true;
-            for part in segment.split(|x| *x == newline) {
-                let line = unsafe { str::from_utf8_unchecked(part) };
-                let line = if is_only_whitespace(line) {
-                    line
-                } else {
-                    line.trim_start()
+    /// Outermost group in the lookahead does not fit - print it broken and reconsider the rest.
+    fn break_lookahead(&mut self) -> std::io::Result<()> {
+        let mut segments = std::mem::take(&mut self.lookahead).into_iter();
+        self.group = Builder::default();
+        self.lookahead_bytes = 0;
+
+        if let Some((segment, kind)) = segments.next() {
+            // Empty block is never split, so its opening stays attached too
+            let kind = match (kind, segments.as_slice().first()) {
+                (SegmentKind::Opening(index, BlockStyle::Allman), Some((_, SegmentKind::Closing))) =>
+                    SegmentKind::Opening(index, BlockStyle::BreakBoth),
+                _ => kind,
+            };
+            self.print_segment(&segment, kind)?;
+        }
+        for (segment, kind) in segments {
+            self.push_segment(&segment, kind)?;
+        }
+
+        Ok(())
+    }
+
+    /// Prints the document of a group on a single line.
+    fn print_flat(&mut self, docs: &[Doc<(Vec<u8>, SegmentKind)>]) -> std::io::Result<()> {
+        self.begin_content()?;
+
+        let depth = self.blocks.len();
+        let mut paints = Vec::new();
+        Doc::flatten(docs, &mut |piece| {
+            let (segment, kind) = match piece {
+                Flat::Text((segment, kind)) => (segment, *kind),
+                Flat::Space => return self.write(" ".as_bytes()),
+            };
+
+            self.paint = match kind {
+                SegmentKind::Closing => paints.pop().flatten(),
+                _ => self.paint_for(kind, depth + paints.len()),
+            };
+            self.write_content(segment)?;
+            if matches!(kind, SegmentKind::Opening(..)) {
+                paints.push(self.paint);
+            }
+            self.paint = None;
+            Ok(())
+        })?;
+
+        self.was_literal = false;
+        Ok(())
+    }
+
+    /// `depth` is the count of blocks around the segment.
+    fn paint_for(&self, kind: SegmentKind, depth: usize) -> Option<Paint> {
+        let palette = self.palette.as_ref()?;
+
+        let by_depth = || palette.brackets.get(depth % palette.brackets.len().max(1)).copied();
+
+        match kind {
+            SegmentKind::Opening(index, _) => palette.pairs.get(index).copied().flatten().or_else(by_depth),
+            SegmentKind::Closing => by_depth(),
+            SegmentKind::Literal(index) => palette.pairs.get(index).copied().flatten(),
+            _ => None,
+        }
+    }
+
+    fn print_segment(&mut self, segment: &[u8], kind: SegmentKind) -> std::io::Result<()> {
+        self.paint = match kind {
+            SegmentKind::Closing => self.blocks.last().map_or_else(|| self.paint_for(kind, 0), |block| block.paint),
+            _ => self.paint_for(kind, self.blocks.len()),
+        };
+
+        match kind {
+            SegmentKind::Text if self.blocks.last().is_some_and(|block| block.style == BlockStyle::Lisp) => {
+                self.push_elements(segment)?
+            },
+            SegmentKind::Text => self.push_text(segment)?,
+            SegmentKind::Literal(_) => self.push_literal(segment)?,
+
+            SegmentKind::Opening(_, style) => {
+                if style == BlockStyle::Allman && !self.is_at_line_start {
+                    self.pending_whitespace.clear();
+                    self.pending_break = true;
+                }
+
+                // Opening pattern itself still belongs to the outer block
+                self.push_text(segment)?;
+
+                // Blocks are indented relative to the enclosing one, hanging blocks are aligned to the opening
+                let outer_indent = self.current_indent();
+                let indent = match style {
+                    BlockStyle::Hang | BlockStyle::Lisp => self.line_indent.aligned(self.column),
+                    _ => outer_indent.nested(),
                 };
+                self.blocks.push(Block {
+                    style,
+                    indent,
+                    outer_indent,
+                    started: false,
+                    separators: 0,
+                    paint: self.paint,
+                });
+
+                self.pending_break = matches!(style, BlockStyle::BreakBoth | BlockStyle::BreakAfterOpen | BlockStyle::Allman);
+            },
+
+            SegmentKind::Closing if segment.is_empty() => {
+                self.blocks.pop();
+            },
+
+            SegmentKind::Closing => {
+                let block = self.blocks.pop();
+                self.pending_separator = false;
+
+                match block.as_ref().map(|block| (block.style, block.started)) {
+                    // Empty block is never split
+                    Some((_, false)) => self.pending_break = false,
+                    Some((BlockStyle::BreakBoth | BlockStyle::Allman, _)) | None => {
+                        self.pending_break = !self.is_at_line_start;
+                    },
+                    // Closing is attached to the last line of the block (or to the opening if the block is empty).
+                    // For S-expressions that stacks closings of nested blocks on a single line.
+                    Some(_) => self.pending_break = false,
+                }
+
+                let indent = block.map_or_else(|| self.current_indent(), |block| block.outer_indent);
+                self.begin_content_at(indent)?;
+                self.push_text(segment)?;
+            },
+
+            SegmentKind::Delimiter(style) => {
+                // Delimiter is attached to the preceding element
+                self.pending_whitespace.clear();
+                if self.is_at_line_start || self.pending_break {
+                    self.begin_content()?;
+                }
+                match style {
+                    DelimiterStyle::Break => {
+                        // Whitespace of the delimiter itself ends up at the end of the line
+                        let text = unsafe { str::from_utf8_unchecked(segment) };
+                        let content = text.trim_end();
+                        self.write_content(content.as_bytes())?;
+                        self.pending_whitespace.push_str(&text[content.len()..]);
+                        self.pending_break = true;
+                    },
+                    DelimiterStyle::Space => {
+                        self.write_content(segment)?;
+                        self.pending_space = true;
+                    },
+                }
+            },
+
+            SegmentKind::RecordEnd => {
+                // Next record starts from scratch, whatever was left open in this one
+                self.blocks.clear();
+                self.pending_break = false;
+                self.pending_separator = false;
+                self.pending_space = false;
+                self.pending_whitespace.clear();
 
-                if self.prev_indent != indentation_level && !self.is_at_line_start && is_closing {
-                    self.write("\n".as_bytes())?;
+                if !segment.starts_with(b"\n") {
+                    self.write_pending_prefix()?;
                 }
-                
-                if !is_first_line {
-                    self.write("\n".as_bytes())?;
+                self.write(segment)?;
+                if segment.contains(&b'\n') {
+                    self.prefix = None;
+                    self.is_prefix_pending = false;
                 }
-                is_first_line = false;
+            },
 
-                if line.len() > 0 {
-                    if self.is_at_line_start {
-                        for _ in 0..indentation_level {
-                            write!(self.output, "\t")?;
+            SegmentKind::Prefix(style) => {
+                let text = unsafe { str::from_utf8_unchecked(segment) };
+                self.is_prefix_pending = false;
+                self.prefix = (!text.is_empty()).then(|| match style {
+                    PrefixStyle::Repeat => text.to_owned(),
+                    // Wide characters take two columns, tabs are kept as they are
+                    PrefixStyle::Align => text.chars()
+                        .map(|c| match c {
+                            '\t' => "\t".to_owned(),
+                            c => " ".repeat(c.width().unwrap_or(0)),
+                        })
+                        .collect(),
+                });
+
+                if !text.is_empty() {
+                    // Line of the input starts a line of the output, even if its line break was not kept
+                    if !self.is_at_line_start {
+                        self.pending_break = false;
+                        self.pending_space = false;
+                        if self.layout.trim_trailing_whitespace {
+                            self.pending_whitespace.clear();
                         }
+                        self.flush_whitespace()?;
+                        self.write("\n".as_bytes())?;
                     }
-                    self.write(line.as_bytes())?;
+                    // Prefix is not a part of the text, so the line still counts as empty
+                    self.output.write_all(segment)?;
                 }
+            },
+        }
 
-                if self.prev_indent != indentation_level && !self.is_at_line_start && !is_closing {
-                    self.write("\n".as_bytes())?;
+        self.paint = None;
+        self.was_literal = matches!(kind, SegmentKind::Literal(_));
+        Ok(())
+    }
+
+    fn current_indent(&self) -> Indent {
+        self.blocks.last().map_or(Indent::default(), |block| block.indent)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.output.write_all(bytes)?;
+        self.track(bytes);
+        Ok(())
+    }
+
+    /// Writes visible text of a segment in its paint.
+    fn write_content(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let Some(paint) = self.paint.filter(|_| !bytes.is_empty()) else {
+            return self.write(bytes);
+        };
+
+        let text = unsafe { str::from_utf8_unchecked(bytes) };
+        write!(self.output, "{}", paint.apply(text))?;
+        self.track(bytes);
+        Ok(())
+    }
+
+    /// Updates position of the cursor after `bytes` were written.
+    fn track(&mut self, bytes: &[u8]) {
+        const NEWLINE: u8 = '\n'.as_ascii().unwrap().to_u8();
+
+        let text = unsafe { str::from_utf8_unchecked(bytes) };
+        match bytes.iter().rposition(|x| *x == NEWLINE) {
+            Some(pos) => {
+                self.line_indent = Indent::default();
+                self.is_wrapped = false;
+                self.column = display_width(&text[pos + 1..]);
+            },
+            None => self.column += display_width(text),
+        }
+        self.is_at_line_start = bytes.last().cloned() == Some(NEWLINE);
+        if bytes.iter().any(|x| *x != NEWLINE) {
+            self.blank_lines = 0;
+        }
+    }
+
+    fn begin_content(&mut self) -> std::io::Result<()> {
+        if let Some(block) = self.blocks.last_mut() {
+            block.started = true;
+
+            if self.pending_separator {
+                // First argument of an S-expression stays on the line of its head, the rest are aligned under it
+                if self.is_at_line_start {
+                    // Already on a new line (after a comment)
+                } else if block.separators == 0 {
+                    block.indent = self.line_indent.aligned(self.column + 1);
+                    self.output.write_all(" ".as_bytes())?;
+                    self.column += 1;
+                } else {
+                    self.pending_break = true;
                 }
+                block.separators += 1;
+            }
+        }
+        self.pending_separator = false;
+
+        self.begin_content_at(self.current_indent())
+    }
+
+    /// Starts a new line if a block asked for it, then indents the line if nothing was written on it yet.
+    fn begin_content_at(&mut self, indent: Indent) -> std::io::Result<()> {
+        if self.pending_break && self.layout.trim_trailing_whitespace {
+            self.pending_whitespace.clear();
+        }
+        self.flush_whitespace()?;
+
+        if self.pending_break {
+            self.pending_break = false;
+            self.pending_space = false;
+            self.write_line_break()?;
+        }
+
+        if self.pending_space {
+            self.pending_space = false;
+            if !self.is_at_line_start {
+                self.write(" ".as_bytes())?;
             }
         }
 
-        self.was_literal = is_literal;
-        self.prev_indent = indentation_level;
+        if self.is_at_line_start {
+            self.write_pending_prefix()?;
+            self.write_indent(indent)?;
+        }
+
+        Ok(())
+    }
+
+    /// Indents the line that was just started.
+    fn write_indent(&mut self, indent: Indent) -> std::io::Result<()> {
+        let text = format!("{}{}", "\t".repeat(indent.tabs), " ".repeat(indent.spaces));
+        self.write(text.as_bytes())?;
+        self.line_indent = indent;
+        self.column = 0;
+        Ok(())
+    }
+
+    /// Starts a new line of the input line, its prefix waits for the content.
+    fn write_line_break(&mut self) -> std::io::Result<()> {
+        self.write("\n".as_bytes())?;
+        self.is_prefix_pending = self.prefix.is_some();
+        Ok(())
+    }
+
+    fn write_pending_prefix(&mut self) -> std::io::Result<()> {
+        if let Some(prefix) = self.prefix.as_ref().filter(|_| self.is_prefix_pending) {
+            self.output.write_all(prefix.as_bytes())?;
+        }
+        self.is_prefix_pending = false;
+        Ok(())
+    }
+
+    /// Breaks the line if content of the given width would not fit in `max_width` after the pending whitespace.
+    /// Wrapped line is indented one level deeper than the line it continues.
+    fn wrap_before(&mut self, width: usize) -> std::io::Result<()> {
+        let Some(max_width) = self.layout.max_width else {
+            return Ok(());
+        };
+        // Nothing to wrap yet, or the line is going to be broken anyway
+        if self.column == 0 || self.pending_break || self.layout.reindent_only {
+            return Ok(());
+        }
+        // S-expressions are laid out by `min_block_size`
+        if self.blocks.last().is_some_and(|block| block.style == BlockStyle::Lisp) {
+            return Ok(());
+        }
+
+        let prefix_width = self.prefix.as_deref().map_or(0, display_width);
+        let spacing = display_width(&self.pending_whitespace) + usize::from(self.pending_space);
+        if prefix_width + self.line_indent.width() + self.column + spacing + width <= max_width {
+            return Ok(());
+        }
+
+        let indent = match self.is_wrapped {
+            true => self.line_indent,
+            false => self.line_indent.nested(),
+        };
+        self.pending_whitespace.clear();
+        self.pending_space = false;
+        self.write_line_break()?;
+
+        self.write_indent(indent)?;
+        self.is_wrapped = true;
+        Ok(())
+    }
+
+    fn flush_whitespace(&mut self) -> std::io::Result<()> {
+        let whitespace = std::mem::take(&mut self.pending_whitespace);
+        self.write(whitespace.as_bytes())
+    }
+
+    /// Literals are never broken, but may be moved to the next line as a whole.
+    /// The scanner sends a literal in parts, so they are held until its first line is complete.
+    fn hold_literal(&mut self, segment: &[u8], kind: SegmentKind) -> std::io::Result<()> {
+        self.literal.push((segment.to_vec(), kind));
+
+        let held = self.literal.iter().map(|(segment, _)| segment.len()).sum::<usize>();
+        if segment.contains(&b'\n') || held > self.layout.max_lookahead {
+            self.flush_literal()?;
+        }
+        Ok(())
+    }
+
+    /// Prints the held start of a literal, on the next line if it does not fit on the current one.
+    fn flush_literal(&mut self) -> std::io::Result<()> {
+        if self.literal.is_empty() {
+            return Ok(());
+        }
+
+        let segments = std::mem::take(&mut self.literal);
+        let mut width = 0;
+        for (segment, _) in &segments {
+            let text = unsafe { str::from_utf8_unchecked(segment) };
+            let first_line = text.split('\n').next().unwrap_or_default();
+            width += display_width(first_line);
+            if first_line.len() < text.len() {
+                break;
+            }
+        }
+        self.wrap_before(width)?;
+
+        for (segment, kind) in segments {
+            self.print_segment(&segment, kind)?;
+        }
+        Ok(())
+    }
+
+    fn push_literal(&mut self, segment: &[u8]) -> std::io::Result<()> {
+        if !self.was_literal {
+            self.begin_content()?;
+        }
+
+        self.write_content(segment)
+    }
+
+    fn push_text(&mut self, segment: &[u8]) -> std::io::Result<()> {
+        let newline: u8 = '\n'.as_ascii().unwrap().to_u8();
+
+        let mut is_first_line = true;
+        for part in segment.split(|x| *x == newline) {
+            if !is_first_line {
+                self.push_newline()?;
+            }
+            is_first_line = false;
+
+            let line = unsafe { str::from_utf8_unchecked(part) };
+            let line = if self.is_at_line_start || self.pending_break || self.pending_space {
+                line.trim_start()
+            } else {
+                line
+            };
+
+            let content = line.trim_end();
+            for (is_whitespace, run) in whitespace_runs(content) {
+                if is_whitespace {
+                    self.pending_whitespace.push_str(run);
+                    continue;
+                }
+
+                self.wrap_before(display_width(run))?;
+                self.begin_content()?;
+                self.write_content(run.as_bytes())?;
+            }
+            self.pending_whitespace.push_str(&line[content.len()..]);
+        }
+
+        Ok(())
+    }
+
+    /// Line break of the input.
+    fn push_newline(&mut self) -> std::io::Result<()> {
+        // Line break of the input also satisfies the one requested by a block
+        if self.layout.trim_trailing_whitespace {
+            self.pending_whitespace.clear();
+        }
+        self.flush_whitespace()?;
+        self.pending_break = false;
+        self.pending_space = false;
+
+        if self.is_at_line_start {
+            if self.layout.max_blank_lines.is_some_and(|max| self.blank_lines >= max) {
+                return Ok(());
+            }
+            self.blank_lines += 1;
+        }
+        self.write_line_break()
+    }
+
+    /// Text inside of a broken S-expression: whitespace only separates elements, that are laid out by the printer.
+    fn push_elements(&mut self, segment: &[u8]) -> std::io::Result<()> {
+        let text = unsafe { str::from_utf8_unchecked(segment) };
+
+        for (is_whitespace, run) in whitespace_runs(text) {
+            if is_whitespace {
+                self.pending_separator = self.blocks.last().is_some_and(|block| block.started);
+
+                if self.layout.keep_line_breaks {
+                    for _ in run.matches('\n') {
+                        self.push_newline()?;
+                    }
+                }
+            } else {
+                self.begin_content()?;
+                self.write_content(run.as_bytes())?;
+            }
+        }
 
         Ok(())
     }
@@ -92,6 +849,148 @@ impl<P: Write> Printer<P> {
     }
 }
 
-fn is_only_whitespace(text: &str) -> bool {
-    text.chars().all(|c| c.is_whitespace())
-}
\ No newline at end of file
+/// Width of the part of `segment` that sticks to a closing right before it, like `;` or `,`.
+fn attached_width(segment: &[u8], kind: SegmentKind) -> usize {
+    let text = unsafe { str::from_utf8_unchecked(segment) };
+    match kind {
+        SegmentKind::Text => match whitespace_runs(text).next() {
+            Some((false, run)) => display_width(run),
+            _ => 0,
+        },
+        SegmentKind::Delimiter(_) => display_width(text),
+        _ => 0,
+    }
+}
+
+/// Splits text into alternating runs of whitespace and non-whitespace characters.
+fn whitespace_runs(text: &str) -> impl Iterator<Item = (bool, &str)> {
+    let mut rest = text;
+
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let is_whitespace = first.is_whitespace();
+        let len = rest.find(|c: char| c.is_whitespace() != is_whitespace).unwrap_or(rest.len());
+
+        let (run, tail) = rest.split_at(len);
+        rest = tail;
+        Some((is_whitespace, run))
+    })
+}
+
+/// Width of the text in a terminal.
+fn display_width(text: &str) -> usize {
+    text.chars()
+        .map(|c| match c {
+            '\t' => TAB_WIDTH,
+            c => c.width().unwrap_or(0),
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn print(spacing: Spacing, segments: &[(&str, SegmentKind)]) -> String {
+        let layout = Layout {
+            min_block_size: 20,
+            max_width: None,
+            max_lookahead: 4096,
+            keep_line_breaks: false,
+            max_blank_lines: None,
+            reindent_only: false,
+            trim_trailing_whitespace: false,
+            spacing,
+        };
+        let mut printer = Printer::new(layout, None, Vec::new());
+        for (segment, kind) in segments {
+            printer.push_segment(segment.as_bytes(), *kind).unwrap();
+        }
+        printer.finish().unwrap();
+        String::from_utf8(printer.output).unwrap()
+    }
+
+    #[test]
+    fn spacing_skips_literals() {
+        let spacing = Spacing { around: vec!["=".to_owned()], ..Spacing::default() };
+        let segments = [
+            ("x=", SegmentKind::Text),
+            ("\"", SegmentKind::Literal(0)),
+            ("a=b", SegmentKind::Literal(0)),
+            ("\"", SegmentKind::Literal(0)),
+            (";", SegmentKind::Text),
+        ];
+        assert_eq!(print(spacing, &segments), "x = \"a=b\";");
+    }
+
+    #[test]
+    fn blocks_are_indented_by_the_enclosing_block() {
+        let style = BlockStyle::BreakAfterOpen;
+        let segments = [
+            ("f", SegmentKind::Text),
+            ("(", SegmentKind::Opening(0, style)),
+            ("aaaaaaaaaa bbbbbbbbbbbbbb", SegmentKind::Text),
+            (")", SegmentKind::Closing),
+            (" ", SegmentKind::Text),
+            ("{", SegmentKind::Opening(1, style)),
+            ("x = 1; yyyyyyyyyyyyyyyyyy", SegmentKind::Text),
+            ("}", SegmentKind::Closing),
+        ];
+        assert_eq!(print(Spacing::default(), &segments), "f(\n\taaaaaaaaaa bbbbbbbbbbbbbb) {\n\tx = 1; yyyyyyyyyyyyyyyyyy}");
+    }
+
+    #[test]
+    fn every_input_line_has_its_own_prefix() {
+        let prefix = SegmentKind::Prefix(PrefixStyle::Repeat);
+        let segments = [
+            ("10:00 A ", prefix),
+            ("a ", SegmentKind::Text),
+            ("{", SegmentKind::Opening(0, BlockStyle::BreakBoth)),
+            ("xxxxxxxxxx yyyyyyyyyyyyyy", SegmentKind::Text),
+            ("}", SegmentKind::Closing),
+            ("\n", SegmentKind::Text),
+            ("10:01 B ", prefix),
+            ("b ", SegmentKind::Text),
+            ("{", SegmentKind::Opening(0, BlockStyle::BreakBoth)),
+            ("xxxxxxxxxx yyyyyyyyyyyyyy", SegmentKind::Text),
+            ("}", SegmentKind::Closing),
+            ("\n", SegmentKind::Text),
+            ("", prefix),
+            ("c", SegmentKind::Text),
+            ("\n", SegmentKind::Text),
+        ];
+        assert_eq!(
+            print(Spacing::default(), &segments),
+            "10:00 A a {\n10:00 A \txxxxxxxxxx yyyyyyyyyyyyyy\n10:00 A }\n\
+             10:01 B b {\n10:01 B \txxxxxxxxxx yyyyyyyyyyyyyy\n10:01 B }\n\
+             c\n"
+        );
+    }
+
+    #[test]
+    fn aligned_prefix_has_the_width_of_the_prefix() {
+        let segments = [
+            ("日本 INFO\t", SegmentKind::Prefix(PrefixStyle::Align)),
+            ("a ", SegmentKind::Text),
+            ("{", SegmentKind::Opening(0, BlockStyle::BreakBoth)),
+            ("xxxxxxxxxx yyyyyyyyyyyyyy", SegmentKind::Text),
+            ("}", SegmentKind::Closing),
+        ];
+        assert_eq!(
+            print(Spacing::default(), &segments),
+            "日本 INFO\ta {\n         \t\txxxxxxxxxx yyyyyyyyyyyyyy\n         \t}"
+        );
+    }
+
+    #[test]
+    fn whitespace_next_to_brackets_does_not_count_in_the_fit() {
+        let segments = [
+            ("f", SegmentKind::Text),
+            ("(", SegmentKind::Opening(0, BlockStyle::BreakBoth)),
+            (" aaaaaaaaaaaaaaaaa ", SegmentKind::Text),
+            (")", SegmentKind::Closing),
+            (";", SegmentKind::Text),
+        ];
+        assert_eq!(print(Spacing::default(), &segments), "f(aaaaaaaaaaaaaaaaa);");
+    }
+}
@@ -9,30 +9,45 @@ pub struct SlidingWindow<R: Read>
     end: usize,
     reader: R,
     end_was_hit: bool,
+    /// Return whatever a single read of the inner reader gives, instead of waiting for the whole window
+    partial: bool,
 }
 
 impl<R: Read> SlidingWindow<R> {
     pub fn new(window_size: usize, reader: R) -> Self {
-        let result = Self { 
+        Self { 
             data: vec![0u8; window_size * 2 - 1].into_boxed_slice(),
             start: 0,
             end: 0,
             reader,
             end_was_hit: false,
-        };
+            partial: false,
+        }
+    }
+
+    /// In partial mode the inner reader is read at most once per `fill`, and only by it,
+    /// so that a live stream never blocks while there is something in the window.
+    pub fn set_partial(&mut self, partial: bool) {
+        self.partial = partial;
+    }
 
-        result
+    pub fn is_full(&self) -> bool {
+        self.end - self.start >= self.window_size()
+    }
+
+    pub fn has_ended(&self) -> bool {
+        self.end_was_hit
     }
 
     fn window_size(&self) -> usize {
-        (self.data.len() + 1) / 2
+        self.data.len().div_ceil(2)
     }
 
     pub fn get_window(&self) -> &[u8] {
-        return &self.data[self.start..self.end];
+        &self.data[self.start..self.end]
     }
     pub fn get_window_utf8(&self) -> &str {
-        let arr = &self.get_window()[..];
+        let arr = self.get_window();
 
         match str::from_utf8(arr) {
             Ok(valid) => valid,
@@ -45,15 +60,19 @@ impl<R: Read> SlidingWindow<R> {
         }
     }
 
-    fn inner_read(end_was_hit: &mut bool, reader: &mut R, mut buf: &mut [u8]) -> std::io::Result<usize> {
+    fn inner_read(end_was_hit: &mut bool, reader: &mut R, mut buf: &mut [u8], partial: bool) -> std::io::Result<usize> {
         let mut total_read = 0;
 
-        while !*end_was_hit && buf.len() > 0 {
+        while !*end_was_hit && !buf.is_empty() {
             let was_read = reader.read(buf)?;
             *end_was_hit = was_read == 0;
 
             total_read += was_read;
             buf = &mut buf[was_read..];
+
+            if partial {
+                break;
+            }
         }
 
         Ok(total_read)
@@ -61,10 +80,17 @@ impl<R: Read> SlidingWindow<R> {
 
     pub fn fill(&mut self) -> std::io::Result<()> {
         let window_size = self.window_size();
-        let bytes_missing = window_size - (self.end - self.start);
+        let stored_size = self.end - self.start;
+
+        if stored_size < window_size && !self.end_was_hit {
+            if self.start + window_size > self.data.len() {
+                self.data.copy_within(self.start..self.end, 0);
+                self.start = 0;
+                self.end = stored_size;
+            }
 
-        if bytes_missing > 0 && !self.end_was_hit {
-            self.end += Self::inner_read(&mut self.end_was_hit, &mut self.reader, &mut self.data[self.start..bytes_missing])?;
+            let window_end = self.start + window_size;
+            self.end += Self::inner_read(&mut self.end_was_hit, &mut self.reader, &mut self.data[self.end..window_end], self.partial)?;
         }
         Ok(())
     }
@@ -93,20 +119,28 @@ impl<R: Read> Read for SlidingWindow<R> {
         let stored_size = self.get_window().len();
         let window_size = self.window_size();
 
+        if self.partial {
+            // Only what is already in the window, the rest is read by `fill`
+            let len = buf.len().min(stored_size);
+            buf[..len].copy_from_slice(&self.get_window()[..len]);
+            self.start += len;
+            return Ok(len);
+        }
+
         if buf.len() >= stored_size {
             // 1. Memcopy 0..N from the window.
             buf[..stored_size].copy_from_slice(self.get_window());
             total_read += stored_size;
 
             // 2. Read N..end from the inner reader.
-            let was_read = Self::inner_read(&mut self.end_was_hit, &mut self.reader, &mut buf[stored_size..])?;
+            let was_read = Self::inner_read(&mut self.end_was_hit, &mut self.reader, &mut buf[stored_size..], false)?;
             total_read += was_read;
 
             // 3. Repopulate entire window from the start
             self.start = 0;
             self.end = 0;
 
-            let was_read = Self::inner_read(&mut self.end_was_hit, &mut self.reader, &mut self.data[..window_size])?;
+            let was_read = Self::inner_read(&mut self.end_was_hit, &mut self.reader, &mut self.data[..window_size], false)?;
             self.end += was_read;
         } else {
             // 1. Memcopy 0..end from the window
@@ -117,14 +151,14 @@ impl<R: Read> Read for SlidingWindow<R> {
             let stored_size = self.end - self.start;
 
             if (self.end + buf_len) <= (self.window_size() * 2 - 1) {
-                let was_read = Self::inner_read(&mut self.end_was_hit, &mut self.reader, &mut self.data[self.end..(self.end + buf_len)])?;
+                let was_read = Self::inner_read(&mut self.end_was_hit, &mut self.reader, &mut self.data[self.end..(self.end + buf_len)], false)?;
                 self.end += was_read;
             } else {
                 self.data.copy_within(self.start..self.end, 0);
                 self.start = 0;
                 self.end = stored_size;
 
-                let was_read = Self::inner_read(&mut self.end_was_hit, &mut self.reader, &mut self.data[self.end..window_size])?;
+                let was_read = Self::inner_read(&mut self.end_was_hit, &mut self.reader, &mut self.data[self.end..window_size], false)?;
                 self.end += was_read;
             }
         }
@@ -0,0 +1,172 @@
+/// Characters that make up operators. Runs of them are a single token, so `==` is never split into `= =`.
+const OPERATOR_CHARS: &str = "!%&*+-/:<=>?^|~";
+
+/// Prefix operators that may directly follow another operator, like `-` in `a=-1`.
+const UNARY_CHARS: &str = "!&*+-~";
+
+/// Spacing of tokens in the text outside of literals.
+#[derive(Debug, Clone, Default)]
+pub struct Spacing {
+    /// Operators that get exactly one space on both sides
+    pub around: Vec<String>,
+    /// Tokens that get no space before and exactly one space after, like `,`
+    pub after: Vec<String>,
+    /// Other runs of whitespace inside of a line become a single space
+    pub collapse: bool,
+}
+
+impl Spacing {
+    pub fn is_empty(&self) -> bool {
+        self.around.is_empty() && self.after.is_empty() && !self.collapse
+    }
+
+    /// Line breaks are kept as is, indentation is left to the printer.
+    pub fn apply(&self, text: &str) -> String {
+        let mut result = String::with_capacity(text.len());
+        // Whitespace before the current token
+        let mut space: Option<&str> = None;
+        // Previous token asked for a space after itself
+        let mut space_after = false;
+
+        for token in tokens(text).flat_map(|token| self.split_unary(token)) {
+            if token.starts_with(char::is_whitespace) {
+                if token.contains('\n') {
+                    result.push_str(token);
+                    space = None;
+                    space_after = false;
+                } else {
+                    space = Some(token);
+                }
+                continue;
+            }
+
+            let is_around = self.around.iter().any(|op| op == token);
+            let is_after = self.after.iter().any(|op| op == token);
+
+            if is_around || (space_after && !is_after) {
+                result.push(' ');
+            } else if let Some(space) = space.filter(|_| !is_after) {
+                result.push_str(if self.collapse { " " } else { space });
+            }
+            result.push_str(token);
+
+            space = None;
+            space_after = is_around || is_after;
+        }
+
+        if space_after {
+            result.push(' ');
+        } else if let Some(space) = space {
+            result.push_str(if self.collapse { " " } else { space });
+        }
+        result
+    }
+
+    /// Where a piece of text may be cut, so that both parts are spaced the same as the whole:
+    /// before the last whitespace that follows a word.
+    pub fn last_cut(text: &str) -> Option<usize> {
+        let is_word = |c: char| !c.is_whitespace() && !OPERATOR_CHARS.contains(c) && !matches!(c, ',' | ';');
+
+        let mut chars = text.char_indices().rev().peekable();
+        while let Some((pos, c)) = chars.next() {
+            if c.is_whitespace() && chars.peek().is_some_and(|&(_, prev)| is_word(prev)) {
+                return Some(pos);
+            }
+        }
+        None
+    }
+
+    /// Splits an operator run into a known operator and the unary operators after it (`=-` into `=` and `-`).
+    fn split_unary<'a>(&self, token: &'a str) -> Vec<&'a str> {
+        let is_known = |op: &str| self.around.iter().chain(&self.after).any(|known| known == op);
+        if !token.starts_with(|c| OPERATOR_CHARS.contains(c)) || is_known(token) {
+            return vec![token];
+        }
+
+        let split = (1..token.len())
+            .rev()
+            .find(|&len| is_known(&token[..len]) && token[len..].chars().all(|c| UNARY_CHARS.contains(c)));
+        match split {
+            Some(len) => vec![&token[..len], &token[len..]],
+            None => vec![token],
+        }
+    }
+}
+
+/// Splits text into runs of whitespace, runs of operator characters, single `,` and `;`, and words.
+fn tokens(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let len = match first {
+            ',' | ';' => 1,
+            c if c.is_whitespace() => rest.find(|c: char| !c.is_whitespace()).unwrap_or(rest.len()),
+            c if OPERATOR_CHARS.contains(c) => rest.find(|c: char| !OPERATOR_CHARS.contains(c)).unwrap_or(rest.len()),
+            _ => rest
+                .find(|c: char| c.is_whitespace() || OPERATOR_CHARS.contains(c) || matches!(c, ',' | ';'))
+                .unwrap_or(rest.len()),
+        };
+
+        let (token, tail) = rest.split_at(len);
+        rest = tail;
+        Some(token)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized() -> Spacing {
+        Spacing {
+            around: ["=", "+", "->"].map(str::to_owned).to_vec(),
+            after: [",", ":"].map(str::to_owned).to_vec(),
+            collapse: true,
+        }
+    }
+
+    #[test]
+    fn spaces_around_operators() {
+        assert_eq!(normalized().apply("a=1+b"), "a = 1 + b");
+        assert_eq!(normalized().apply("f->g"), "f -> g");
+    }
+
+    #[test]
+    fn longer_operators_are_kept() {
+        assert_eq!(normalized().apply("a==b"), "a==b");
+        assert_eq!(normalized().apply("a += b"), "a += b");
+        assert_eq!(normalized().apply("a=>b"), "a=>b");
+    }
+
+    #[test]
+    fn unary_operators_after_known_ones() {
+        assert_eq!(normalized().apply("a=-1"), "a = -1");
+        assert_eq!(normalized().apply("a=!b+-c"), "a = !b + -c");
+    }
+
+    #[test]
+    fn spaces_after_punctuation() {
+        assert_eq!(normalized().apply("a ,b:c"), "a, b: c");
+    }
+
+    #[test]
+    fn line_breaks_are_kept() {
+        assert_eq!(normalized().apply("a,\n  b"), "a,\n  b");
+    }
+
+    #[test]
+    fn whitespace_is_collapsed_only_on_request() {
+        assert_eq!(normalized().apply("a   b"), "a b");
+        assert_eq!(Spacing { collapse: false, ..normalized() }.apply("a   b"), "a   b");
+    }
+
+    #[test]
+    fn text_is_cut_after_a_word() {
+        assert_eq!(Spacing::last_cut("aaa x=="), Some(3));
+        assert_eq!(Spacing::last_cut("a, b  "), Some(4));
+        assert_eq!(Spacing::last_cut("a ,  "), Some(1));
+        assert_eq!(Spacing::last_cut("+ ,  "), None);
+        assert_eq!(Spacing::last_cut("x=="), None);
+    }
+}
@@ -0,0 +1,56 @@
+use std::{io::{ErrorKind, Read}, sync::mpsc::{self, Receiver, RecvTimeoutError}, thread, time::Duration};
+
+/// Reads the inner reader on a separate thread, so that waiting for input can time out.
+/// A read that waited longer than the timeout fails with `ErrorKind::TimedOut` and may be retried.
+pub struct TimeoutReader {
+    /// Chunks read by the thread. Empty chunk means the end of input
+    receiver: Receiver<std::io::Result<Vec<u8>>>,
+    /// Part of the last chunk that did not fit into the buffer of a read
+    pending: Vec<u8>,
+    pending_start: usize,
+    timeout: Duration,
+}
+
+impl TimeoutReader {
+    pub fn new<R: Read + Send + 'static>(mut reader: R, timeout: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut buf = vec![0u8; 8 * 1024];
+            loop {
+                let chunk = match reader.read(&mut buf) {
+                    Ok(was_read) => Ok(buf[..was_read].to_vec()),
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => Err(err),
+                };
+                let is_last = !matches!(&chunk, Ok(chunk) if !chunk.is_empty());
+
+                if sender.send(chunk).is_err() || is_last {
+                    break;
+                }
+            }
+        });
+
+        Self { receiver, pending: Vec::new(), pending_start: 0, timeout }
+    }
+}
+
+impl Read for TimeoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending_start >= self.pending.len() {
+            self.pending = match self.receiver.recv_timeout(self.timeout) {
+                Ok(chunk) => chunk?,
+                Err(RecvTimeoutError::Timeout) => return Err(ErrorKind::TimedOut.into()),
+                Err(RecvTimeoutError::Disconnected) => Vec::new(),
+            };
+            self.pending_start = 0;
+        }
+
+        let pending = &self.pending[self.pending_start..];
+        let len = buf.len().min(pending.len());
+        buf[..len].copy_from_slice(&pending[..len]);
+        self.pending_start += len;
+
+        Ok(len)
+    }
+}